@@ -8,7 +8,64 @@ pub fn wrmsr(address: u64, value: u64) {
 pub fn rdmsr(address: u64) -> u64 {
     let (high, low): (u32, u32);
     unsafe {
-        core::arch::asm!("wrmsr", in("ecx") address, out("eax") low, out("edx") high, options(nostack, preserves_flags, nomem))
+        core::arch::asm!("rdmsr", in("ecx") address, out("eax") low, out("edx") high, options(nostack, preserves_flags, nomem))
     }
     (high as u64) << 32 | low as u64
 }
+pub fn cpuid(leaf: u32, sub_leaf: u32) -> (u32, u32, u32, u32) {
+    #[allow(unused_unsafe)]
+    let res = unsafe { core::arch::x86_64::__cpuid_count(leaf, sub_leaf) };
+    (res.eax, res.ebx, res.ecx, res.edx)
+}
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+pub fn read_cr0() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+pub fn read_cr2() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+pub fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+pub fn write_cr4(value: u64) {
+    unsafe {
+        core::arch::asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+pub fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+pub fn write_cr3(value: u64) {
+    unsafe {
+        core::arch::asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+pub fn invlpg(addr: u64) {
+    unsafe {
+        core::arch::asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags));
+    }
+}
+pub fn invpcid(kind: u64, pcid: u64, addr: u64) {
+    let descriptor: [u64; 2] = [pcid, addr];
+    unsafe {
+        core::arch::asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor, options(nostack, preserves_flags));
+    }
+}
@@ -1,5 +1,9 @@
+use crate::memory::slab::{SlabBox, SlabCache};
+
 pub const BLOCK_SIZE: usize = 512;
 
+static BUF_CACHE: SlabCache<Buf> = SlabCache::new("buf");
+
 pub struct Buf {
     pub flag: Flag,
     pub device: Device,
@@ -30,4 +34,34 @@ impl Buf {
             data: [0; 512],
         }
     }
+
+    pub fn alloc(block_num: usize) -> SlabBox<Self> {
+        BUF_CACHE.boxed(Self::new(block_num))
+    }
+}
+
+#[test_case]
+fn test_buf_cache() {
+    use alloc::vec::Vec;
+
+    let before = BUF_CACHE.stats();
+    // one more than a slab holds
+    let mut bufs: Vec<_> = (0..before.objects_per_slab + 1).map(Buf::alloc).collect();
+    for (i, buf) in bufs.iter_mut().enumerate() {
+        assert!(buf.block_num == i && buf.data.iter().all(|&b| b == 0));
+        buf.data.fill(i as u8);
+    }
+    // no two buffers share memory
+    for (i, buf) in bufs.iter().enumerate() {
+        assert!(buf.data.iter().all(|&b| b == i as u8));
+    }
+    let stats = BUF_CACHE.stats();
+    assert!(stats.objects_in_use == before.objects_in_use + bufs.len());
+    assert!(stats.slabs >= 2);
+
+    drop(bufs);
+    let stats = BUF_CACHE.stats();
+    assert!(stats.objects_in_use == before.objects_in_use);
+    // empty slabs are released, at most one is kept around
+    assert!(stats.slabs <= before.slabs + 1);
 }
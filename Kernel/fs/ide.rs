@@ -1,16 +1,38 @@
-use core::sync::atomic::AtomicBool;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    time::Duration,
+};
 
-use crate::utils::port::Port;
+use crate::{
+    interrupts::{
+        irq::{irq_count, register_irq},
+        is_enable,
+        softirq::{in_softirq, schedule_tasklet},
+    },
+    time::{busy_wait, now_ns},
+    utils::port::Port,
+};
 
 use super::buf::{Buf, BLOCK_SIZE};
 
 static mut IDE_LOCK: AtomicBool = AtomicBool::new(false);
+// buffer of the read in flight, filled by `ide_complete`
+static CURRENT_BUF: AtomicPtr<Buf> = AtomicPtr::new(null_mut());
 
 const SECTOR_SIZE: usize = 512;
+// primary channel
+const IDE_IRQ: u8 = 14;
 
 const IDE_BUSY: u8 = 0x80;
 const IDE_READY: u8 = 0x40;
 
+// how long a drive gets to answer
+const DETECT_TIMEOUT: Duration = Duration::from_millis(10);
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+const IRQ_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_micros(10);
+
 const INT_PORT: Port = Port::new(0x3f6);
 const DATA_PORT: Port = Port::new(0x1f0);
 const CMD_PORT: Port = Port::new(0x1f7);
@@ -20,34 +42,48 @@ const LSB1_PORT: Port = Port::new(0x1f3);
 const LSB2_PORT: Port = Port::new(0x1f4);
 const LSB3_PORT: Port = Port::new(0x1f5);
 
-pub fn ide_wait() {
+/// Poll `done` every `POLL_INTERVAL` until it holds, false if `timeout` passed first
+fn poll(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = now_ns() + timeout.as_nanos() as u64;
     loop {
-        if CMD_PORT.read_u8() & (IDE_BUSY | IDE_READY) == IDE_READY {
-            break;
+        if done() {
+            return true;
+        }
+        if now_ns() >= deadline {
+            return false;
         }
+        busy_wait(POLL_INTERVAL);
     }
 }
 
+/// Wait until the drive is ready for a command, false if it never gets there
+pub fn ide_wait() -> bool {
+    poll(READY_TIMEOUT, || {
+        CMD_PORT.read_u8() & (IDE_BUSY | IDE_READY) == IDE_READY
+    })
+}
+
 pub fn ide_init() {
     // check if disk 1 is present
     // 0xe0 5 and 7 bit must be 1, 6 bit 1 -> LBA, 0 -> CHS
     DEV_PORT.write_u8(0xe0 | (1 << 4));
-    let mut flag = false;
-    for _ in 0..1000 {
-        if CMD_PORT.read_u8() != 0 {
-            println!("disk1 detected!");
-            flag = true;
-            break;
-        }
-    }
-    if !flag {
+    if poll(DETECT_TIMEOUT, || CMD_PORT.read_u8() != 0) {
+        println!("disk1 detected!");
+    } else {
         println!("disk1 undetected!");
     }
     // back to disk0
     DEV_PORT.write_u8(0xe0 | (0 << 4));
+    register_irq(IDE_IRQ, ide_intr, 0);
 }
 
+/// Read or write `buf`, waits for the IDE interrupt and so needs a task
+/// running with interrupts enabled, its completion is a tasklet
 pub fn ide_start(buf: &Buf) {
+    assert!(
+        is_enable() && !in_softirq(),
+        "ide: ide_start called with interrupts disabled or from a softirq, the read would never complete"
+    );
     // FIXME: how to restrain the block_num
     let sector_per_block = BLOCK_SIZE / SECTOR_SIZE;
     let sector = buf.block_num * sector_per_block;
@@ -67,34 +103,54 @@ pub fn ide_start(buf: &Buf) {
 
     // drive select
     DEV_PORT.write_u8(0xe0 | slave_flag | ((sector >> 24) & 0x0f) as u8);
-    ide_wait();
+    assert!(
+        ide_wait(),
+        "ide: drive is not ready for block {}",
+        buf.block_num
+    );
 
     match buf.flag {
         super::buf::Flag::Read => {
+            CURRENT_BUF.store(buf as *const Buf as *mut Buf, Ordering::SeqCst);
             unsafe {
-                IDE_LOCK.store(true, core::sync::atomic::Ordering::SeqCst);
+                IDE_LOCK.store(true, Ordering::SeqCst);
             }
             CMD_PORT.write_u8(0x20);
-            // // waiting interrupt
-            unsafe {
-                loop {
-                    if !IDE_LOCK.load(core::sync::atomic::Ordering::SeqCst) {
-                        break;
-                    }
-                }
-            }
-
-            DATA_PORT.read_u32_to(&buf.data as *const _ as *const u32, BLOCK_SIZE / 4);
+            // waiting for ide_complete
+            let done = poll(IRQ_TIMEOUT, || unsafe { !IDE_LOCK.load(Ordering::SeqCst) });
+            // tells a missing interrupt from one no handler claimed
+            let (count, unhandled) = irq_count(IDE_IRQ);
+            assert!(
+                done,
+                "ide: no interrupt for block {}, irq {} fired {} times, {} unhandled",
+                buf.block_num, IDE_IRQ, count, unhandled
+            );
         }
         super::buf::Flag::Dirty => todo!(),
     }
 }
 
-pub fn ide_intr() {
+/// Acknowledge the drive, the data is read by `ide_complete` after the handler returned
+pub fn ide_intr(_ctx: usize) -> bool {
     unsafe {
-        if IDE_LOCK.load(core::sync::atomic::Ordering::SeqCst) {
+        if IDE_LOCK.load(Ordering::SeqCst) {
+            // reading the status clears the interrupt
             CMD_PORT.read_u8();
-            IDE_LOCK.store(false, core::sync::atomic::Ordering::SeqCst);
+            schedule_tasklet(ide_complete, 0);
+            return true;
         }
     }
+    false
+}
+
+/// Copy the sector the drive has ready into the waiting buffer and release it
+fn ide_complete(_ctx: usize) {
+    let buf = CURRENT_BUF.swap(null_mut(), Ordering::SeqCst);
+    if !buf.is_null() {
+        DATA_PORT.read_u32_to(
+            unsafe { &(*buf).data } as *const _ as *const u32,
+            BLOCK_SIZE / 4,
+        );
+    }
+    unsafe { IDE_LOCK.store(false, Ordering::SeqCst) };
 }
@@ -7,7 +7,7 @@ use self::ide::ide_start;
 
 pub fn test_ide_read() {
     for i in 0..10 {
-        let buf = Buf::new(i);
+        let buf = Buf::alloc(i);
         ide_start(&buf);
         log!("{i}: {:x?}", buf.data);
     }
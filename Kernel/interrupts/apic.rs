@@ -0,0 +1,95 @@
+use crate::{
+    arch::instruction::{rdmsr, wrmsr},
+    memory::mmio::{ioremap, CacheMode, IoMem},
+    sync::once::Once,
+};
+
+const MSR_IA32_APIC_BASE: u64 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// register offsets
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+// the APIC may raise this instead of a masked interrupt, it must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+pub static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// The local APIC of the boot processor
+pub struct LocalApic {
+    regs: IoMem,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Counts down from the initial count once
+    OneShot,
+    /// Fires when the TSC reaches IA32_TSC_DEADLINE
+    TscDeadline,
+}
+
+impl LocalApic {
+    /// Map the registers at `phys` and turn the APIC on
+    pub fn new(phys: u64) -> Self {
+        wrmsr(
+            MSR_IA32_APIC_BASE,
+            rdmsr(MSR_IA32_APIC_BASE) | APIC_BASE_ENABLE,
+        );
+        let apic = Self {
+            regs: ioremap(phys, 0x400, CacheMode::Uncached),
+        };
+        // accept every priority
+        apic.regs.write::<u32>(REG_TPR, 0);
+        apic.regs
+            .write::<u32>(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        apic
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.regs.read::<u32>(REG_ID) >> 24) as u8
+    }
+
+    /// Signal the end of the interrupt being handled
+    pub fn eoi(&self) {
+        self.regs.write::<u32>(REG_EOI, 0);
+    }
+
+    /// Stop the timer and put it in `mode`, a masked timer still counts
+    pub fn setup_timer(&self, mode: TimerMode, masked: bool) {
+        self.stop_timer();
+        self.regs.write::<u32>(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+        let mut lvt = TIMER_VECTOR as u32;
+        if mode == TimerMode::TscDeadline {
+            lvt |= LVT_TSC_DEADLINE;
+        }
+        if masked {
+            lvt |= LVT_MASKED;
+        }
+        self.regs.write::<u32>(REG_LVT_TIMER, lvt);
+    }
+
+    /// Count down from `count` in one-shot mode, in steps of 16 bus cycles
+    pub fn start_timer(&self, count: u32) {
+        self.regs.write::<u32>(REG_TIMER_INITIAL, count);
+    }
+
+    pub fn stop_timer(&self) {
+        self.regs.write::<u32>(REG_TIMER_INITIAL, 0);
+    }
+
+    pub fn timer_count(&self) -> u32 {
+        self.regs.read::<u32>(REG_TIMER_CURRENT)
+    }
+}
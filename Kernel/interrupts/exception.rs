@@ -0,0 +1,262 @@
+use core::fmt;
+
+use super::handler::exception_dispatch;
+
+pub const EXCEPTION_COUNT: usize = 32;
+
+/// Everything `exception_common` saves, the CPU pushed the part from `rip` on
+#[derive(Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    pub vector: u64,
+    // 0 for exceptions without an error code
+    pub error_code: u64,
+
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Whether the exception interrupted user mode
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "rip: {:#018X} cs: {:#X} rflags: {:#X} rsp: {:#018X} ss: {:#X}",
+            self.rip, self.cs, self.rflags, self.rsp, self.ss
+        )?;
+        writeln!(
+            f,
+            "rax: {:#018X} rbx: {:#018X} rcx: {:#018X} rdx: {:#018X}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "rsi: {:#018X} rdi: {:#018X} rbp: {:#018X} r8:  {:#018X}",
+            self.rsi, self.rdi, self.rbp, self.r8
+        )?;
+        writeln!(
+            f,
+            "r9:  {:#018X} r10: {:#018X} r11: {:#018X} r12: {:#018X}",
+            self.r9, self.r10, self.r11, self.r12
+        )?;
+        write!(
+            f,
+            "r13: {:#018X} r14: {:#018X} r15: {:#018X}",
+            self.r13, self.r14, self.r15
+        )
+    }
+}
+
+/// Save the general purpose registers behind the vector and error code
+/// pushed by the stub, call `exception_dispatch` and return to the interrupted code
+#[naked]
+extern "C" fn exception_common() -> ! {
+    unsafe {
+        core::arch::asm!(
+            "
+            push rax
+            push rbx
+            push rcx
+            push rdx
+            push rsi
+            push rdi
+            push rbp
+            push r8
+            push r9
+            push r10
+            push r11
+            push r12
+            push r13
+            push r14
+            push r15
+
+            cld
+            mov rdi, rsp
+            // the CPU only aligns the stack to 16 bytes before pushing its frame
+            mov rbx, rsp
+            and rsp, -16
+            call {dispatch}
+            mov rsp, rbx
+
+            pop r15
+            pop r14
+            pop r13
+            pop r12
+            pop r11
+            pop r10
+            pop r9
+            pop r8
+            pop rbp
+            pop rdi
+            pop rsi
+            pop rdx
+            pop rcx
+            pop rbx
+            pop rax
+
+            // vector and error code
+            add rsp, 16
+            iretq
+            ",
+            dispatch = sym exception_dispatch,
+            options(noreturn)
+        )
+    }
+}
+
+macro_rules! exception_stub {
+    ($name:ident, $vector:expr) => {
+        #[naked]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                core::arch::asm!(
+                    // keep the frame layout of exceptions with an error code
+                    "push 0",
+                    "push {vector}",
+                    "jmp {common}",
+                    vector = const $vector,
+                    common = sym exception_common,
+                    options(noreturn)
+                )
+            }
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[naked]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                core::arch::asm!(
+                    "push {vector}",
+                    "jmp {common}",
+                    vector = const $vector,
+                    common = sym exception_common,
+                    options(noreturn)
+                )
+            }
+        }
+    };
+}
+
+exception_stub!(divide_error, 0);
+exception_stub!(debug, 1);
+exception_stub!(non_maskable_interrupt, 2);
+exception_stub!(breakpoint, 3);
+exception_stub!(overflow, 4);
+exception_stub!(bound_range_exceeded, 5);
+exception_stub!(invalid_opcode, 6);
+exception_stub!(device_not_available, 7);
+exception_stub!(double_fault, 8, error_code);
+exception_stub!(coprocessor_segment_overrun, 9);
+exception_stub!(invalid_tss, 10, error_code);
+exception_stub!(segment_not_present, 11, error_code);
+exception_stub!(stack_segment_fault, 12, error_code);
+exception_stub!(general_protection_fault, 13, error_code);
+exception_stub!(page_fault, 14, error_code);
+exception_stub!(reserved_15, 15);
+exception_stub!(x87_floating_point, 16);
+exception_stub!(alignment_check, 17, error_code);
+exception_stub!(machine_check, 18);
+exception_stub!(simd_floating_point, 19);
+exception_stub!(virtualization, 20);
+exception_stub!(control_protection, 21, error_code);
+exception_stub!(reserved_22, 22);
+exception_stub!(reserved_23, 23);
+exception_stub!(reserved_24, 24);
+exception_stub!(reserved_25, 25);
+exception_stub!(reserved_26, 26);
+exception_stub!(reserved_27, 27);
+exception_stub!(hypervisor_injection, 28);
+exception_stub!(vmm_communication, 29, error_code);
+exception_stub!(security, 30, error_code);
+exception_stub!(reserved_31, 31);
+
+/// The IDT entries of all exceptions, indexed by vector
+pub const EXCEPTION_ENTRIES: [extern "C" fn() -> !; EXCEPTION_COUNT] = [
+    divide_error,
+    debug,
+    non_maskable_interrupt,
+    breakpoint,
+    overflow,
+    bound_range_exceeded,
+    invalid_opcode,
+    device_not_available,
+    double_fault,
+    coprocessor_segment_overrun,
+    invalid_tss,
+    segment_not_present,
+    stack_segment_fault,
+    general_protection_fault,
+    page_fault,
+    reserved_15,
+    x87_floating_point,
+    alignment_check,
+    machine_check,
+    simd_floating_point,
+    virtualization,
+    control_protection,
+    reserved_22,
+    reserved_23,
+    reserved_24,
+    reserved_25,
+    reserved_26,
+    reserved_27,
+    hypervisor_injection,
+    vmm_communication,
+    security,
+    reserved_31,
+];
+
+pub fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        2 => "NMI",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        9 => "COPROCESSOR SEGMENT OVERRUN",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "X87 FLOATING POINT",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        20 => "VIRTUALIZATION",
+        21 => "CONTROL PROTECTION",
+        28 => "HYPERVISOR INJECTION",
+        29 => "VMM COMMUNICATION",
+        30 => "SECURITY",
+        _ => "RESERVED",
+    }
+}
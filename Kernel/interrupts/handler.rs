@@ -1,101 +1,129 @@
-use crate::{fs::ide::ide_intr, hlt, interrupts::pic::PIC, proc::sheduler::SCHEDULAR};
+use crate::{
+    arch::instruction::{read_cr0, read_cr2, read_cr3, read_cr4},
+    memory::{stack::guard_page_owner, vma::handle_page_fault},
+    proc::sheduler::SCHEDULAR,
+    utils::backtrace::backtrace,
+};
 
-use super::idt::ExceptionFrame;
-pub extern "x86-interrupt" fn divide_zero_handler(frame: ExceptionFrame) {
-    log!("EXCEPTION: DIVIDE BY ZERO");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt();
-}
+use super::{
+    exception::{exception_name, TrapFrame},
+    idt::ExceptionFrame,
+};
 
-pub extern "x86-interrupt" fn non_maskable_interrupt(frame: ExceptionFrame) {
-    log!("EXCEPTION: NMI FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn break_point_interrupt(frame: ExceptionFrame) {
-    log!("EXCEPTION: BREAKPOINT FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn overflow_interrupt(frame: ExceptionFrame) {
-    log!("EXCEPTION: OVERFLOW FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn bound_range_exceeded_interrupt(frame: ExceptionFrame) {
-    log!("EXCEPTION: BOUND_RANGE_EXCEEDED FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn invalid_opcode_interrupt(frame: ExceptionFrame) {
-    log!("EXCEPTION: INVALID OPCODE FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn invalid_tss_interrupt(frame: ExceptionFrame, error_code: u64) {
-    log!("errorcode: {}", error_code);
-    log!("EXCEPTION: INVALID TSS FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn segment_not_present_interrupt(
-    frame: ExceptionFrame,
-    error_code: u64,
-) {
-    log!("errorcode: {}", error_code);
-    log!("EXCEPTION: SEGMENT_NOT_PRESENT FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
-pub extern "x86-interrupt" fn stack_segment_fault_interrupt(
-    frame: ExceptionFrame,
-    error_code: u64,
-) {
-    log!("errorcode: {}", error_code);
-    log!("EXCEPTION: STACK_SEGMENT_FAULT FAULT");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt()
-}
+const DEBUG: u64 = 1;
+const NMI: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+const MACHINE_CHECK: u64 = 18;
 
-pub extern "x86-interrupt" fn page_fault_handler(frame: ExceptionFrame, error_code: u64) {
-    handle_page_fault_errorcode(error_code);
-    let cr2: u64;
-    unsafe {
-        core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+/// Called by `exception_common` for every CPU exception, returning resumes
+/// the interrupted code with the registers in `frame`
+pub extern "C" fn exception_dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        PAGE_FAULT => {
+            let cr2 = read_cr2();
+            if handle_page_fault(cr2, frame.error_code) {
+                // the page is there now, retry the instruction
+                return;
+            }
+            if let Some(owner) = guard_page_owner(cr2) {
+                dump_frame(frame);
+                panic!("stack overflow in {} at {:#X}", owner, cr2);
+            }
+            handle_page_fault_errorcode(frame.error_code);
+            log!("trying to access addr {:#X}", cr2);
+        }
+        DOUBLE_FAULT => {
+            // a page fault on a guard page can't push its frame on the overflowed stack and ends up here
+            let cr2 = read_cr2();
+            if let Some(owner) = guard_page_owner(cr2).or_else(|| guard_page_owner(frame.rsp)) {
+                dump_frame(frame);
+                panic!("stack overflow in {} at {:#X}", owner, cr2);
+            }
+        }
+        DEBUG | BREAKPOINT | NMI => {
+            log!(
+                "EXCEPTION: {} at {:#X}",
+                exception_name(frame.vector),
+                frame.rip
+            );
+            return;
+        }
+        _ => {}
     }
-    log!("trying to access addr {:#X}", cr2);
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt();
-}
 
-pub extern "x86-interrupt" fn double_fault_handler(frame: ExceptionFrame, error_code: u64) {
-    // the error_code is related to segment fault, which is quite useless
-    log!("EXCEPTION: DOUBLE FAULT, errorcode: {error_code}");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt();
-}
+    // aborts leave nothing to return to, whoever was running
+    let abort = frame.vector == DOUBLE_FAULT || frame.vector == MACHINE_CHECK;
+    if frame.is_user() && !abort {
+        log!(
+            "EXCEPTION: {} in user mode at {:#X}, error code {:#X}",
+            exception_name(frame.vector),
+            frame.rip,
+            frame.error_code
+        );
+        SCHEDULAR.exit_current();
+    }
 
-pub extern "x86-interrupt" fn disk_interrupt_handler(_frame: ExceptionFrame) {
-    ide_intr();
-    PIC.eof(0x2e);
+    dump_frame(frame);
+    backtrace(frame.rbp as *const u64);
+    panic!(
+        "kernel {} at {:#X}",
+        exception_name(frame.vector),
+        frame.rip
+    );
 }
-pub extern "x86-interrupt" fn timer_interrupt_handler(_frame: ExceptionFrame) {
-    print!(".");
-    // let sched = &*SCHEDULAR;
-    PIC.eof(0x20);
-    // sched.preempt();
+
+/// Log the saved registers, the control registers and the decoded error code
+fn dump_frame(frame: &TrapFrame) {
+    log!(
+        "EXCEPTION: {} ({}), error code {:#X}",
+        exception_name(frame.vector),
+        frame.vector,
+        frame.error_code
+    );
+    log!("{:?}", frame);
+    log!(
+        "cr0: {:#018X} cr2: {:#018X} cr3: {:#018X} cr4: {:#018X}",
+        read_cr0(),
+        read_cr2(),
+        read_cr3(),
+        read_cr4()
+    );
+    // invalid TSS, segment not present, stack segment fault and #GP report a selector
+    if (10..=13).contains(&frame.vector) && frame.error_code != 0 {
+        decode_selector_error(frame.error_code);
+    }
 }
 
-pub extern "x86-interrupt" fn general_protection_fault_handler(
-    frame: ExceptionFrame,
-    error_code: u64,
-) {
-    log!("error code {:#X}", error_code);
-    log!("general protection fault occur!");
-    log!("EXCEPTION MESSAGE: {frame:#?}");
-    hlt();
+/*
+|15         3|  2|  1|  0|
+|       INDEX| TI|IDT|EXT|
+ */
+fn decode_selector_error(error_code: u64) {
+    let index = error_code >> 3 & 0x1fff;
+    let table = if error_code & 1 << 1 != 0 {
+        "IDT"
+    } else if error_code & 1 << 2 != 0 {
+        "LDT"
+    } else {
+        "GDT"
+    };
+    log!(
+        "  selector: {} index {:#X}{}",
+        table,
+        index,
+        if error_code & 1 != 0 {
+            ", external event"
+        } else {
+            ""
+        }
+    );
 }
 
+/// Raised by the local APIC for an interrupt that went away, no EOI must be sent
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_frame: ExceptionFrame) {}
+
 fn handle_page_fault_errorcode(error_code: u64) {
     log!("page fault, ERROR:");
     if error_code & 1 != 0 {
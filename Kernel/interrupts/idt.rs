@@ -1,11 +1,18 @@
 use core::fmt;
 
-use super::handler::{
-    bound_range_exceeded_interrupt, break_point_interrupt, disk_interrupt_handler,
-    divide_zero_handler, double_fault_handler, general_protection_fault_handler,
-    invalid_opcode_interrupt, invalid_tss_interrupt, non_maskable_interrupt, overflow_interrupt,
-    page_fault_handler, segment_not_present_interrupt, stack_segment_fault_interrupt,
-    timer_interrupt_handler,
+use crate::{
+    memory::gdt::{
+        DEBUG_IST_INDEX, DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX,
+    },
+    time::lapic::timer_interrupt,
+};
+
+use super::{
+    apic::{SPURIOUS_VECTOR, TIMER_VECTOR},
+    exception::EXCEPTION_ENTRIES,
+    handler::spurious_interrupt_handler,
+    irq::IRQ_ENTRIES,
+    IRQ_BASE,
 };
 
 /*
@@ -23,8 +30,17 @@ const USER_DATA_SELECTOR_64: u16 = (6 << 3) + (0 << 2) + 0;
 
 static mut IDT: Idt = Idt::new();
 
+// exceptions that can hit while the current stack is unusable, and their IST slot
+const IST_VECTORS: [(usize, u16); 4] = [
+    (0x01, DEBUG_IST_INDEX),
+    (0x02, NMI_IST_INDEX),
+    (0x08, DOUBLE_FAULT_IST_INDEX),
+    (0x12, MACHINE_CHECK_IST_INDEX),
+];
+
 pub struct Idt([Entry; 256]);
-#[repr(C, packed)]
+// every field is naturally aligned, no packing is needed for the 16 byte layout
+#[repr(C)]
 #[derive(Clone, Copy)]
 struct Entry {
     pointer_low: u16,
@@ -36,7 +52,7 @@ struct Entry {
 }
 
 #[derive(Clone, Copy)]
-struct EntryOptions(u16);
+pub struct EntryOptions(u16);
 
 #[repr(C)]
 pub struct ExceptionFrame {
@@ -68,7 +84,6 @@ impl fmt::Debug for ExceptionFrame {
 }
 
 pub type HandlerFunc = extern "x86-interrupt" fn(_: ExceptionFrame);
-pub type HandlerFuncWithErr = extern "x86-interrupt" fn(_: ExceptionFrame, _: u64);
 
 impl EntryOptions {
     fn new() -> Self {
@@ -107,7 +122,7 @@ impl EntryOptions {
         self.0 |= level << 13;
         self
     }
-    #[allow(unused)]
+    /// Switch to IST stack `index` - 1 on entry, 0 keeps the current stack
     pub fn set_stack_index(&mut self, index: u16) -> &mut Self {
         assert!(index < 8);
         self.0 &= !(0b111);
@@ -144,11 +159,18 @@ impl Idt {
     const fn new() -> Self {
         Self([Entry::default(); 256])
     }
-    pub fn set_handler_with_errorcode(&mut self, entry: usize, handler: HandlerFuncWithErr) {
+    pub fn set_handler(&mut self, entry: usize, handler: HandlerFunc) -> &mut EntryOptions {
         self.0[entry] = Entry::new(handler as u64);
+        &mut self.0[entry].options
     }
-    pub fn set_handler(&mut self, entry: usize, handler: HandlerFunc) {
+    /// Jump straight to `handler`, which saves and restores everything itself
+    pub fn set_raw_handler(
+        &mut self,
+        entry: usize,
+        handler: extern "C" fn() -> !,
+    ) -> &mut EntryOptions {
         self.0[entry] = Entry::new(handler as u64);
+        &mut self.0[entry].options
     }
     pub fn load(&self) {
         #[derive(Debug)]
@@ -169,20 +191,18 @@ impl Idt {
 
 pub fn init_idt() {
     unsafe {
-        IDT.set_handler(0x0, divide_zero_handler);
-        IDT.set_handler(0x2, non_maskable_interrupt);
-        IDT.set_handler(0x3, break_point_interrupt);
-        IDT.set_handler(0x4, overflow_interrupt);
-        IDT.set_handler(0x5, bound_range_exceeded_interrupt);
-        IDT.set_handler(0x6, invalid_opcode_interrupt);
-        IDT.set_handler_with_errorcode(0x08, double_fault_handler);
-        IDT.set_handler_with_errorcode(0x09, invalid_tss_interrupt);
-        IDT.set_handler_with_errorcode(0x0A, segment_not_present_interrupt);
-        IDT.set_handler_with_errorcode(0x0B, stack_segment_fault_interrupt);
-        IDT.set_handler_with_errorcode(0x0E, page_fault_handler);
-        IDT.set_handler_with_errorcode(0x0D, general_protection_fault_handler);
-        IDT.set_handler(0x20, timer_interrupt_handler);
-        IDT.set_handler(0x2e, disk_interrupt_handler);
+        for (vector, entry) in EXCEPTION_ENTRIES.iter().copied().enumerate() {
+            let options = IDT.set_raw_handler(vector, entry);
+            // e.g. a stack overflow double faults, its handler needs a stack that still works
+            if let Some(&(_, ist_index)) = IST_VECTORS.iter().find(|&&(v, _)| v == vector) {
+                options.set_stack_index(ist_index + 1);
+            }
+        }
+        for (irq, entry) in IRQ_ENTRIES.iter().copied().enumerate() {
+            IDT.set_handler(IRQ_BASE as usize + irq, entry);
+        }
+        IDT.set_handler(TIMER_VECTOR as usize, timer_interrupt);
+        IDT.set_handler(SPURIOUS_VECTOR as usize, spurious_interrupt_handler);
         IDT.load();
     }
 }
@@ -0,0 +1,169 @@
+use alloc::vec::Vec;
+
+use crate::{
+    memory::mmio::{ioremap, CacheMode, IoMem},
+    sync::{once::Once, spin::SpinMutex},
+    utils::acpi::{IrqOverride, Madt},
+};
+
+use super::run_without_interrupt;
+
+// the index of a register is written to IOREGSEL, its value is accessed through IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+// redirection entry bits
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+static OVERRIDES: Once<Vec<IrqOverride>> = Once::new();
+
+pub struct IoApic {
+    regs: IoMem,
+    // IOREGSEL and IOWIN have to be used as a pair
+    lock: SpinMutex<()>,
+    gsi_base: u32,
+    entries: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Map the IOAPICs and remember the ISA overrides listed in the MADT
+pub fn init(madt: &Madt) {
+    let apics = madt
+        .io_apics
+        .iter()
+        .map(|io| {
+            let apic = IoApic::new(io.address, io.gsi_base);
+            log!(
+                "ioapic {}: {:#X}, GSI {}-{}",
+                io.id,
+                io.address,
+                apic.gsi_base,
+                apic.gsi_base + apic.entries - 1
+            );
+            apic
+        })
+        .collect();
+    let _ = IO_APICS.call_once(|| Ok::<_, ()>(apics));
+    let _ = OVERRIDES.call_once(|| Ok::<_, ()>(madt.overrides.clone()));
+}
+
+/// GSI, polarity and trigger mode of ISA IRQ `irq`, ISA lines are
+/// active high and edge triggered unless the firmware says otherwise
+pub fn isa_irq_route(irq: u8) -> (u32, Polarity, Trigger) {
+    let over = OVERRIDES
+        .get()
+        .and_then(|overrides| overrides.iter().find(|o| o.source == irq));
+    let over = match over {
+        Some(over) => over,
+        None => return (irq as u32, Polarity::ActiveHigh, Trigger::Edge),
+    };
+    // 0b00 conforms to the bus, 0b01 high/edge, 0b11 low/level
+    let polarity = match over.flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match over.flags >> 2 & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    (over.gsi, polarity, trigger)
+}
+
+/// Deliver `gsi` as `vector` to the local APIC `dest`, the line stays masked
+pub fn route(gsi: u32, vector: u8, dest: u8, polarity: Polarity, trigger: Trigger) {
+    let mut entry = vector as u64 | (dest as u64) << 56 | MASKED;
+    if polarity == Polarity::ActiveLow {
+        entry |= ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        entry |= LEVEL_TRIGGERED;
+    }
+    let (apic, index) = find(gsi);
+    apic.write_entry(index, entry);
+}
+
+pub fn set_masked(gsi: u32, masked: bool) {
+    let (apic, index) = find(gsi);
+    let entry = apic.read_entry(index);
+    let entry = if masked {
+        entry | MASKED
+    } else {
+        entry & !MASKED
+    };
+    apic.write_entry(index, entry);
+}
+
+/// The IOAPIC handling `gsi` and the index of its redirection entry
+fn find(gsi: u32) -> (&'static IoApic, u32) {
+    IO_APICS
+        .get()
+        .and_then(|apics| {
+            apics
+                .iter()
+                .find(|apic| (apic.gsi_base..apic.gsi_base + apic.entries).contains(&gsi))
+        })
+        .map(|apic| (apic, gsi - apic.gsi_base))
+        .unwrap_or_else(|| panic!("no IOAPIC handles GSI {}", gsi))
+}
+
+impl IoApic {
+    fn new(phys: u64, gsi_base: u32) -> Self {
+        let mut apic = Self {
+            regs: ioremap(phys, 0x20, CacheMode::Uncached),
+            lock: SpinMutex::new(()),
+            gsi_base,
+            entries: 0,
+        };
+        apic.entries = (apic.read(REG_VERSION) >> 16 & 0xff) + 1;
+        // nothing is delivered until a line is routed and unmasked
+        for index in 0..apic.entries {
+            apic.write_entry(index, MASKED);
+        }
+        apic
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        run_without_interrupt(|| {
+            let _guard = self.lock.lock();
+            self.regs.write::<u32>(IOREGSEL, reg);
+            self.regs.read::<u32>(IOWIN)
+        })
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        run_without_interrupt(|| {
+            let _guard = self.lock.lock();
+            self.regs.write::<u32>(IOREGSEL, reg);
+            self.regs.write::<u32>(IOWIN, value);
+        })
+    }
+
+    fn read_entry(&self, index: u32) -> u64 {
+        let reg = REG_REDIRECTION + index * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry(&self, index: u32, entry: u64) {
+        let reg = REG_REDIRECTION + index * 2;
+        // the low half holds the mask bit, keep the line masked while the entry is incomplete
+        self.write(reg, MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
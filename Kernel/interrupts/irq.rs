@@ -0,0 +1,146 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::spin::SpinMutex;
+
+use super::{eoi, idt::ExceptionFrame, irq_exit, run_without_interrupt, set_line_masked};
+
+pub const IRQ_COUNT: usize = 16;
+// handlers that can share one line
+const MAX_SHARED: usize = 4;
+
+/// Called with the `ctx` it was registered with, returns whether
+/// its device raised the interrupt
+pub type IrqHandler = fn(ctx: usize) -> bool;
+
+/// A handler added by `register_irq`, given back to `unregister_irq` to remove it
+#[derive(Debug)]
+pub struct IrqHandle {
+    irq: u8,
+    // index in the actions of the line
+    slot: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Action {
+    handler: IrqHandler,
+    ctx: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    // fixed size, so drivers can register before the heap exists
+    actions: [Option<Action>; MAX_SHARED],
+}
+
+static LINES: [SpinMutex<Line>; IRQ_COUNT] = [const {
+    SpinMutex::new(Line {
+        actions: [None; MAX_SHARED],
+    })
+}; IRQ_COUNT];
+static COUNTS: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+// interrupts no handler claimed
+static UNHANDLED: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+
+/// Entry of vector `IRQ_BASE + IRQ` in the IDT
+pub extern "x86-interrupt" fn irq_entry<const IRQ: u8>(_frame: ExceptionFrame) {
+    dispatch(IRQ);
+    irq_exit();
+}
+
+/// The IDT entries of all IRQs, indexed by IRQ
+pub const IRQ_ENTRIES: [extern "x86-interrupt" fn(ExceptionFrame); IRQ_COUNT] = [
+    irq_entry::<0>,
+    irq_entry::<1>,
+    irq_entry::<2>,
+    irq_entry::<3>,
+    irq_entry::<4>,
+    irq_entry::<5>,
+    irq_entry::<6>,
+    irq_entry::<7>,
+    irq_entry::<8>,
+    irq_entry::<9>,
+    irq_entry::<10>,
+    irq_entry::<11>,
+    irq_entry::<12>,
+    irq_entry::<13>,
+    irq_entry::<14>,
+    irq_entry::<15>,
+];
+
+/// Call every handler of `irq`, then acknowledge it
+fn dispatch(irq: u8) {
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    // handlers run with interrupts disabled, nobody else can hold the lock
+    let line = *LINES[irq as usize].lock();
+    let mut handled = false;
+    for action in line.actions.iter().flatten() {
+        handled |= (action.handler)(action.ctx);
+    }
+    if !handled {
+        UNHANDLED[irq as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    eoi(irq);
+}
+
+/// Call `handler` with `ctx` whenever `irq` fires, the line is unmasked
+/// with its first handler. Several handlers can share a line.
+pub fn register_irq(irq: u8, handler: IrqHandler, ctx: usize) -> IrqHandle {
+    assert!((irq as usize) < IRQ_COUNT, "invalid irq {}", irq);
+    run_without_interrupt(|| {
+        let mut line = LINES[irq as usize].lock();
+        let first = line.actions.iter().all(Option::is_none);
+        let slot = line
+            .actions
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or_else(|| panic!("irq {} has too many handlers", irq));
+        line.actions[slot] = Some(Action { handler, ctx });
+        if first {
+            set_line_masked(irq, false);
+        }
+        IrqHandle { irq, slot }
+    })
+}
+
+/// Remove the handler `handle` was returned for, the line is masked with its last handler
+pub fn unregister_irq(handle: IrqHandle) {
+    run_without_interrupt(|| {
+        let mut line = LINES[handle.irq as usize].lock();
+        assert!(
+            line.actions[handle.slot].take().is_some(),
+            "handler is not registered for irq {}",
+            handle.irq
+        );
+        if line.actions.iter().all(Option::is_none) {
+            set_line_masked(handle.irq, true);
+        }
+    });
+}
+
+/// Stop delivering `irq` without removing its handlers
+pub fn mask_irq(irq: u8) {
+    run_without_interrupt(|| set_line_masked(irq, true));
+}
+
+pub fn unmask_irq(irq: u8) {
+    run_without_interrupt(|| set_line_masked(irq, false));
+}
+
+/// Whether some handler is registered for `irq`
+pub fn has_handlers(irq: u8) -> bool {
+    run_without_interrupt(|| {
+        LINES[irq as usize]
+            .lock()
+            .actions
+            .iter()
+            .any(Option::is_some)
+    })
+}
+
+/// How often `irq` fired and how often no handler claimed it
+pub fn irq_count(irq: u8) -> (u64, u64) {
+    (
+        COUNTS[irq as usize].load(Ordering::Relaxed),
+        UNHANDLED[irq as usize].load(Ordering::Relaxed),
+    )
+}
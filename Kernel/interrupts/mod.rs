@@ -1,20 +1,40 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{proc::sheduler::preempt_on_irq_exit, utils::acpi};
+
+use self::apic::{LocalApic, LOCAL_APIC};
 use self::idt::init_idt;
+use self::irq::{has_handlers, IRQ_COUNT};
 use self::pic::PIC;
+use self::softirq::do_softirq;
 
+pub mod apic;
+pub mod exception;
 mod handler;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
 mod pic;
+pub mod softirq;
+
+// vector of ISA IRQ 0, the others follow
+pub const IRQ_BASE: u8 = 0x20;
+pub const TIMER_IRQ: u8 = 0;
+
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
 
 pub fn run_without_interrupt<F, R>(f: F) -> R
 where
     F: FnOnce() -> R,
 {
-    if is_enable() {
+    let enabled = is_enable();
+    if enabled {
         disable();
     }
     let res = f();
 
-    if !is_enable() {
+    // only restore, the caller may be running with interrupts disabled already
+    if enabled {
         enable();
     }
 
@@ -46,6 +66,14 @@ pub fn enable() {
     }
 }
 
+/// Enable interrupts and wait for the next one. `sti` only takes effect after
+/// the following instruction, so no interrupt can slip in before the `hlt`.
+pub fn enable_and_halt() {
+    unsafe {
+        core::arch::asm!("sti; hlt", options(nomem, nostack));
+    }
+}
+
 #[allow(dead_code)]
 pub fn divide_by_zero() {
     unsafe { core::arch::asm!("mov edx, 0; div edx") }
@@ -57,3 +85,65 @@ pub fn init() {
     PIC.init();
     enable();
 }
+
+/// Move interrupt delivery from the 8259 PIC to the local APIC and IOAPICs
+/// described by the ACPI MADT, needs the memory manager for the registers
+pub fn init_apic() {
+    let madt = match acpi::madt() {
+        Some(madt) if !madt.io_apics.is_empty() => madt,
+        _ => {
+            log!("no IOAPIC found, staying with the 8259 PIC");
+            return;
+        }
+    };
+    run_without_interrupt(|| {
+        // lines of the PIC raise spurious interrupts if they are left open
+        if madt.has_8259 {
+            PIC.disable();
+        }
+        let apic = LOCAL_APIC
+            .call_once(|| Ok::<_, ()>(LocalApic::new(madt.local_apic_address)))
+            .unwrap();
+        ioapic::init(&madt);
+        APIC_ENABLED.store(true, Ordering::Relaxed);
+        // open the lines drivers registered for so far on the IOAPIC
+        for irq in (0..IRQ_COUNT as u8).filter(|&irq| has_handlers(irq)) {
+            set_line_masked(irq, false);
+        }
+        log!(
+            "apic init complete, local apic {} at {:#X}, {} cpus",
+            apic.id(),
+            madt.local_apic_address,
+            madt.local_apic_ids.len()
+        );
+    });
+}
+
+/// Mask or unmask ISA IRQ `irq` at the controller in use,
+/// an IOAPIC line is routed to this CPU before it's unmasked
+fn set_line_masked(irq: u8, masked: bool) {
+    let apic = match LOCAL_APIC.get() {
+        Some(apic) if APIC_ENABLED.load(Ordering::Relaxed) => apic,
+        _ => return PIC.set_masked(irq, masked),
+    };
+    let (gsi, polarity, trigger) = ioapic::isa_irq_route(irq);
+    if !masked {
+        ioapic::route(gsi, IRQ_BASE + irq, apic.id(), polarity, trigger);
+    }
+    ioapic::set_masked(gsi, masked);
+}
+
+/// Common tail of the interrupt handlers, after the EOI: run the softirqs,
+/// then switch tasks if the scheduler asks for it
+pub fn irq_exit() {
+    do_softirq();
+    preempt_on_irq_exit();
+}
+
+/// Acknowledge ISA IRQ `irq` at whichever controller delivered it
+pub fn eoi(irq: u8) {
+    match LOCAL_APIC.get() {
+        Some(apic) if APIC_ENABLED.load(Ordering::Relaxed) => apic.eoi(),
+        _ => PIC.eof(irq),
+    }
+}
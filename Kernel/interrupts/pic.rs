@@ -2,6 +2,9 @@ use crate::utils::port::Port;
 
 pub static PIC: PicController = PicController::new();
 
+const EOI: u8 = 0x20;
+const CASCADE_IRQ: u8 = 2;
+
 pub struct PicController {
     master: Pic,
     slave: Pic,
@@ -39,19 +42,44 @@ impl PicController {
         self.slave.data.write_u8(0x02); // ICW3
         self.slave.data.write_u8(0x01); // ICW4
 
-        self.master.data.write_u8(0xf8); // open IRQ0 - time  IRQ1 - keyboard IRQ2 - slave
-        self.slave.data.write_u8(0x3f); // open IRQ14,15 - ide
+        // every line stays masked until it is asked for
+        self.disable();
 
         log!("pic init complete");
     }
 
-    pub fn eof(&self, index: u16) {
-        assert!(index >= 0x20 && index <= 0x2f);
-        if index >= 0x20 && index < 0x28 {
-            self.master.control.write_u8(0x20);
+    /// Mask or unmask IRQ `irq`, the slave's lines also need the cascade on IRQ2
+    pub fn set_masked(&self, irq: u8, masked: bool) {
+        assert!(irq < 16, "invalid irq {}", irq);
+        let (pic, bit) = if irq < 8 {
+            (&self.master, irq)
+        } else {
+            (&self.slave, irq - 8)
+        };
+        let mask = pic.data.read_u8();
+        let mask = if masked {
+            mask | 1 << bit
         } else {
-            self.master.control.write_u8(0x20);
-            self.slave.control.write_u8(0x20);
+            mask & !(1 << bit)
+        };
+        pic.data.write_u8(mask);
+        if irq >= 8 && !masked {
+            self.set_masked(CASCADE_IRQ, false);
+        }
+    }
+
+    /// Mask every line, used when the APIC takes over
+    pub fn disable(&self) {
+        self.master.data.write_u8(0xff);
+        self.slave.data.write_u8(0xff);
+    }
+
+    /// Acknowledge IRQ `irq`, an IRQ of the slave is acknowledged on both chips
+    pub fn eof(&self, irq: u8) {
+        assert!(irq < 16, "invalid irq {}", irq);
+        if irq >= 8 {
+            self.slave.control.write_u8(EOI);
         }
+        self.master.control.write_u8(EOI);
     }
 }
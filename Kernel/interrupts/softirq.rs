@@ -0,0 +1,87 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{
+    proc::workqueue::{Work, WorkFn, WorkQueue},
+    sync::spin::SpinMutex,
+    time::timer::run_timers,
+};
+
+use super::{disable, enable, run_without_interrupt};
+
+// rounds before the rest is left to the next interrupt
+const MAX_ROUNDS: usize = 10;
+
+/// Deferred halves of interrupt handlers, they run after the hard handler
+/// returned with interrupts enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SoftIrq {
+    /// Expired kernel timers
+    Timer,
+    /// Work queued by interrupt handlers with `schedule_tasklet`
+    Tasklet,
+}
+
+// in order of priority
+const HANDLERS: [(SoftIrq, fn()); 2] = [
+    (SoftIrq::Timer, run_timers),
+    (SoftIrq::Tasklet, run_tasklets),
+];
+
+static TASKLETS: SpinMutex<WorkQueue> = SpinMutex::new(WorkQueue::new());
+
+static PENDING: AtomicU32 = AtomicU32::new(0);
+// set while softirqs run, the interrupts they let in must not start them again
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Whether softirqs are running, maybe below the current interrupt
+pub fn in_softirq() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Run the handler of `softirq` once the current interrupt is done
+pub fn raise(softirq: SoftIrq) {
+    PENDING.fetch_or(1 << softirq as u32, Ordering::Relaxed);
+}
+
+/// Run the pending softirqs, called at the end of a hard handler after the EOI.
+/// Interrupts are enabled while the handlers run and disabled again on return.
+pub fn do_softirq() {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    for _ in 0..MAX_ROUNDS {
+        let pending = PENDING.swap(0, Ordering::Relaxed);
+        if pending == 0 {
+            break;
+        }
+        enable();
+        for &(softirq, handler) in HANDLERS.iter() {
+            if pending & 1 << softirq as u32 != 0 {
+                handler();
+            }
+        }
+        disable();
+    }
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Call `func` with `ctx` in softirq context once the current interrupt is
+/// done. Must not wait, use `schedule_work` for that.
+/// False if the queue was full and the tasklet dropped.
+pub fn schedule_tasklet(func: WorkFn, ctx: usize) -> bool {
+    let queued = run_without_interrupt(|| TASKLETS.lock().push(Work { func, ctx }));
+    // the handler reports dropped tasklets too
+    raise(SoftIrq::Tasklet);
+    queued
+}
+
+fn run_tasklets() {
+    let dropped = run_without_interrupt(|| TASKLETS.lock().take_dropped());
+    if dropped != 0 {
+        log!("softirq: {} tasklets dropped, the queue was full", dropped);
+    }
+    while let Some(work) = run_without_interrupt(|| TASKLETS.lock().pop()) {
+        (work.func)(work.ctx);
+    }
+}
@@ -26,7 +26,7 @@ use core::str::from_raw_parts;
 use fs::{ide, test_ide_read};
 #[allow(unused_imports)]
 use interrupts::divide_by_zero;
-use memory::{frame::Allocator, gdt, read_page, virt_to_physical};
+use memory::{frame::Allocator, gdt, virt_to_physical};
 use proc::{exec, sheduler::SCHEDULAR, user_space_prog_1};
 use utils::PCI;
 use vga::TerminalWriter;
@@ -57,6 +57,8 @@ mod sync;
 
 mod fs;
 
+mod time;
+
 pub const KERNEL_BASE: u64 = 0xFFFFFFFF80000000;
 
 extern "C" {
@@ -99,12 +101,21 @@ pub struct MultibootInfo {
     config_table: u32,
 
     /* Boot Loader Name */
-    boot_loader_name: *const u8,
+    boot_loader_name: u32,
 
     /* APM table */
     apm_table: u32,
 }
 
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct MultibootModule {
+    mod_start: u32,
+    mod_end: u32,
+    string: u32,
+    reserved: u32,
+}
+
 #[repr(C, packed)]
 struct MultibootMmapEntry {
     size: u32,
@@ -139,26 +150,26 @@ pub unsafe extern "C" fn kmain(_multiboot_magic: u64, _info: *const MultibootInf
     // log!("kernel start: {:#X}", start_addr);
     // log!("kernel end: {:#X}", end_addr);
     let mut allocator = Allocator::new(_info, (start_addr, end_addr));
-    memory::init(&mut allocator);
+    memory::init(_info, &mut allocator);
+    interrupts::init_apic();
+    time::init_hpet();
+    time::init_tickless();
 
     // test_ide_read();
 
     PCI.print_all_device();
 
-    // read_page();
+    // memory::info::report();
 
     #[cfg(test)]
     test_main();
 
-    // let sched = &*SCHEDULAR;
-    // sched.preempt();
-
     // exec(user_space_prog_1 as *const () as u64, &mut allocator);
 
-    // test_allocator(_info, (start_addr, end_addr));
-    // test_map(&mut allocator);
     // print_boot_info(_info);
-    hlt();
+
+    // the boot stack is left for good
+    SCHEDULAR.start();
 }
 
 pub fn hlt() -> ! {
@@ -174,14 +185,15 @@ fn init() {
     interrupts::init();
     gdt::init();
     proc::init_syscalls();
+    time::init(time::HZ);
     ide::ide_init();
 }
 
 #[allow(dead_code)]
 fn print_boot_info(info: *const MultibootInfo) {
     unsafe {
-        let ptr = (*info).boot_loader_name;
-        let name = from_raw_parts(ptr.add(KERNEL_BASE as usize), 4);
+        let ptr = ((*info).boot_loader_name as u64 + KERNEL_BASE) as *const u8;
+        let name = from_raw_parts(ptr, 4);
         let flags = (*info).flags;
         let mem_lower = (*info).mem_lower;
         let mem_upper = (*info).mem_upper;
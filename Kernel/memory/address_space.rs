@@ -0,0 +1,364 @@
+use core::ptr::NonNull;
+
+use alloc::boxed::Box;
+
+use crate::sync::spin::SpinMutex;
+
+use super::{
+    cow, entry_create,
+    frame::{Allocator, Frame, FrameAllocator, PageSize},
+    heap_allocator::HEAP_START,
+    info, leaf_entry, lookup, map_page,
+    page_table::{Level4, Page, PageTable, PageTableEntry, TableLevel, P4, RECURSIVE_INDEX},
+    phys_to_virt, remap_page,
+    slab::SlabCache,
+    tlb, unmap_page, unmap_range_in, virt_to_phys,
+    vma::{self, Vma, VmaFlags, VmaList},
+};
+
+static PAGE_TABLE_CACHE: SlabCache<PageTable<Level4>> = SlabCache::new("page_table");
+
+// the heap lives in the lower half, but belongs to the kernel as well
+const HEAP_P4_INDEX: usize = (HEAP_START >> 39) & 0o777;
+
+/// A set of mappings with its own PML4.
+///
+/// The kernel half (and the heap slot) point to the same lower level tables
+/// in every address space, so kernel mappings made in any of them are seen
+/// by all. Mappings can be added to a space that is not loaded, the tables
+/// are reached through the direct map in that case.
+///
+/// Pages of the areas added with `add_area` are owned by the space,
+/// they are mapped on first touch and released with the space.
+pub struct AddressSpace {
+    p4: NonNull<PageTable<Level4>>,
+    pcid: u16,
+    // boxed, so the fault handler can keep pointing to it while the space moves
+    areas: Box<SpinMutex<VmaList>>,
+}
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+/// PML4 slots shared by every address space
+fn is_kernel_slot(index: usize) -> bool {
+    index >= 256 || index == HEAP_P4_INDEX
+}
+
+/// Give every kernel slot of the loaded PML4 a table. The tables are never
+/// freed, so the slots `AddressSpace::new` copies don't change after boot.
+pub fn init_kernel_slots<A>(allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let p4 = unsafe { &mut *P4 };
+    for i in (0..512).filter(|&i| is_kernel_slot(i) && i != RECURSIVE_INDEX) {
+        p4.next_table_create(i, false, allocator);
+    }
+}
+
+/// Whether `addr` is mapped by the tables shared by every address space
+pub fn is_kernel_address(addr: u64) -> bool {
+    is_kernel_slot(((addr >> 39) & 0o777) as usize)
+}
+
+impl AddressSpace {
+    /// Create a space with an empty user half
+    pub fn new() -> Self {
+        let mut p4 = PAGE_TABLE_CACHE
+            .alloc()
+            .expect("cannot allocate page table");
+        let table = unsafe { p4.as_mut() };
+        table.reset();
+        let current = unsafe { &*P4 };
+        for i in (0..512).filter(|&i| is_kernel_slot(i)) {
+            table.entries[i] = current.entries[i];
+        }
+        // NOTE: 510 must be the table's own address!
+        let phys_addr = virt_to_phys(p4.as_ptr() as u64);
+        table.entries[RECURSIVE_INDEX] = PageTableEntry::table(phys_addr, false);
+        let pcid = tlb::alloc_pcid();
+        // the PCID may have been used by a space that is gone
+        tlb::flush_pcid(pcid);
+        Self {
+            p4,
+            pcid,
+            areas: Box::new(SpinMutex::new(VmaList::new())),
+        }
+    }
+
+    /// Physical address of the PML4, the value loaded into CR3
+    pub fn phys_addr(&self) -> u64 {
+        virt_to_phys(self.p4.as_ptr() as u64)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.table().is_active()
+    }
+
+    /// Load this space into CR3
+    pub fn activate(&self) {
+        if !self.is_active() {
+            tlb::switch_to(self.phys_addr(), self.pcid);
+        }
+        vma::set_active_areas(&self.areas);
+    }
+
+    /// Reserve `[start, end)` for memory that is mapped when it is first touched
+    pub fn add_area(&mut self, start: u64, end: u64, flags: VmaFlags) {
+        assert!(
+            !is_kernel_address(start) && !is_kernel_address(end - 1),
+            "area {:#X}..{:#X} is in the kernel half",
+            start,
+            end
+        );
+        assert!(
+            !(flags.writable && flags.executable),
+            "W^X: area {:#X}..{:#X} can't be writable and executable",
+            start,
+            end
+        );
+        self.areas.lock().insert(Vma { start, end, flags });
+    }
+
+    /// Remove the area starting at `start` and release the pages it used
+    #[allow(unused)]
+    pub fn remove_area<A>(&mut self, start: u64, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let vma = self
+            .areas
+            .lock()
+            .remove(start)
+            .unwrap_or_else(|| panic!("no area starts at {:#X}", start));
+        self.unmap_range(vma.start, vma.end, allocator);
+    }
+
+    /// The area containing `addr`
+    #[allow(unused)]
+    pub fn find_area(&self, addr: u64) -> Option<Vma> {
+        self.areas.lock().find(addr).copied()
+    }
+
+    pub fn map<A>(&mut self, page: Page, frame: Frame, allocator: &mut A) -> &mut PageTableEntry
+    where
+        A: FrameAllocator,
+    {
+        map_page(self.table_mut(), page, frame, false, allocator)
+    }
+
+    pub fn map_user<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        allocator: &mut A,
+    ) -> &mut PageTableEntry
+    where
+        A: FrameAllocator,
+    {
+        map_page(self.table_mut(), page, frame, true, allocator)
+    }
+
+    /// See `memory::remap`
+    #[allow(unused)]
+    pub fn remap<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        allocator: &mut A,
+    ) -> (&mut PageTableEntry, Frame)
+    where
+        A: FrameAllocator,
+    {
+        let (active, pcid) = (self.is_active(), self.pcid);
+        let res = remap_page(self.table_mut(), page, frame, allocator);
+        if !active {
+            tlb::flush_pcid(pcid);
+        }
+        res
+    }
+
+    /// See `memory::unmap`
+    #[allow(unused)]
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        unmap_page(self.table_mut(), page, allocator);
+        if !self.is_active() {
+            tlb::flush_pcid(self.pcid);
+        }
+    }
+
+    /// See `memory::unmap_range`
+    #[allow(unused)]
+    pub fn unmap_range<A>(&mut self, start: u64, end: u64, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        unmap_range_in(self.table_mut(), start, end, allocator);
+        if !self.is_active() {
+            tlb::flush_pcid(self.pcid);
+        }
+    }
+
+    /// Physical address `virt_addr` is mapped to in this space
+    #[allow(unused)]
+    pub fn translate(&self, virt_addr: u64) -> Option<u64> {
+        lookup(self.table(), virt_addr)
+            .map(|(entry, size)| entry.frame_addr(size) + (virt_addr & (size as u64 - 1)))
+    }
+
+    /// Get the entry for `page`, creating missing tables on the way
+    #[allow(unused)]
+    pub fn entry<A>(&mut self, page: Page, is_user: bool, allocator: &mut A) -> &mut PageTableEntry
+    where
+        A: FrameAllocator,
+    {
+        entry_create(self.table_mut(), page, is_user, allocator)
+    }
+
+    /// Log the mappings of this space, see `info::dump_mappings`
+    pub fn dump(&self) {
+        info::dump_mappings(self.table());
+    }
+
+    fn table(&self) -> &PageTable<Level4> {
+        unsafe { self.p4.as_ref() }
+    }
+
+    fn table_mut(&mut self) -> &mut PageTable<Level4> {
+        unsafe { self.p4.as_mut() }
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copy of the user half for `fork`. The copy has its own tables, the pages
+/// of the areas are shared copy-on-write and other mappings point to the same
+/// frames, so memory a program writes to has to be in an area.
+impl Clone for AddressSpace {
+    fn clone(&self) -> Self {
+        let mut allocator = Allocator::get();
+        let mut space = AddressSpace::new();
+        let dst = space.table_mut();
+        for i in (0..512).filter(|&i| !is_kernel_slot(i)) {
+            let src_p3 = match self.table().next_table(i) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            dst.entries[i] = self.table().entries[i];
+            let dst_p3 = copy_table(src_p3, &mut dst.entries[i], &mut allocator);
+            for j in 0..512 {
+                let src_p2 = match src_p3.next_table(j) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+                let dst_p2 = copy_table(src_p2, &mut dst_p3.entries[j], &mut allocator);
+                for k in 0..512 {
+                    if let Some(src_p1) = src_p2.next_table(k) {
+                        copy_table(src_p1, &mut dst_p2.entries[k], &mut allocator);
+                    }
+                }
+            }
+        }
+
+        // pages of the areas are shared until one of the spaces writes to them
+        let areas = self.areas.lock().clone();
+        for vma in areas.iter() {
+            let mut addr = vma.start;
+            while addr < vma.end {
+                addr = match leaf_entry(space.table(), addr) {
+                    Err(hole) => (addr & !(hole - 1)).saturating_add(hole),
+                    Ok((entry, PageSize::Small)) => {
+                        let (src, _) = leaf_entry(self.table(), addr).unwrap();
+                        src.set_writable(false).set_cow(true);
+                        *entry = *src;
+                        cow::share(entry.addr());
+                        addr + PageSize::Small as u64
+                    }
+                    Ok((entry, size)) => {
+                        // huge pages are not faulted in, copy them right away
+                        let frame = allocator
+                            .allocate_frame_with_size(size)
+                            .expect("no frame available");
+                        let src = phys_to_virt(entry.frame_addr(size)) as *const u8;
+                        let dst = phys_to_virt(frame.addr) as *mut u8;
+                        unsafe { core::ptr::copy_nonoverlapping(src, dst, size as usize) };
+                        entry.set_addr(frame.addr);
+                        addr + size as u64
+                    }
+                };
+            }
+        }
+        // the pages of this space became read-only
+        if self.is_active() {
+            tlb::flush_all();
+        } else {
+            tlb::flush_pcid(self.pcid);
+        }
+        *space.areas.lock() = areas;
+        space
+    }
+}
+
+/// Copy `table` into a new frame, `entry` is pointed to the copy and keeps its flags
+fn copy_table<L, A>(
+    table: &PageTable<L>,
+    entry: &mut PageTableEntry,
+    allocator: &mut A,
+) -> &'static mut PageTable<L>
+where
+    L: TableLevel,
+    A: FrameAllocator,
+{
+    let frame = allocator.allocate_frame().expect("no frame available");
+    let copy = unsafe { &mut *(phys_to_virt(frame.addr) as *mut PageTable<L>) };
+    copy.entries = table.entries;
+    entry.set_addr(frame.addr);
+    copy
+}
+
+/// Releases the pages of the areas and the tables of the user half,
+/// other mapped frames are left alone
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "cannot drop the loaded address space");
+        vma::clear_active_areas(&self.areas);
+        let mut allocator = Allocator::get();
+        let areas: alloc::vec::Vec<Vma> = self.areas.lock().iter().copied().collect();
+        for vma in areas {
+            unmap_range_in(self.table_mut(), vma.start, vma.end, &mut allocator);
+        }
+        let mut free_table = |entry: &mut PageTableEntry| {
+            allocator.deallocate_frame(Frame::new_small_page(entry.addr()));
+            entry.set_unused();
+        };
+        let p4 = self.table_mut();
+        for i in (0..512).filter(|&i| !is_kernel_slot(i)) {
+            let p3 = match p4.next_table_mut(i) {
+                Some(p3) => p3,
+                None => continue,
+            };
+            for j in 0..512 {
+                let p2 = match p3.next_table_mut(j) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+                for k in 0..512 {
+                    if p2.next_table(k).is_some() {
+                        free_table(&mut p2[k]);
+                    }
+                }
+                free_table(&mut p3[j]);
+            }
+            free_table(&mut p4[i]);
+        }
+        tlb::release_pcid(self.phys_addr(), self.pcid);
+        unsafe { PAGE_TABLE_CACHE.free(self.p4) };
+    }
+}
@@ -0,0 +1,159 @@
+use super::frame::PageSize;
+
+// physical memory above this address is ignored by the allocator
+pub const MAX_PHYS_ADDR: u64 = 0x1_0000_0000;
+pub const FRAME_SIZE: u64 = PageSize::Small as u64;
+// order 18 block = 2^18 * 4K = 1G
+pub const MAX_ORDER: usize = 18;
+
+const MAX_FRAMES: usize = (MAX_PHYS_ADDR / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = bitmap_offset(MAX_ORDER + 1);
+
+/// Buddy allocator over physical frames.
+///
+/// Every order keeps a bitmap with one bit per block of `2^order` frames,
+/// a set bit means the block is free and not merged into its parent.
+/// The bitmaps live in a static array, so the allocator works before
+/// any heap or direct mapping is available.
+pub struct BuddyAllocator {
+    bitmap: [u64; BITMAP_WORDS],
+    free_blocks: [usize; MAX_ORDER + 1],
+    total_frames: usize,
+    free_frames: usize,
+    // bytes handed to `add_range` above `MAX_PHYS_ADDR`
+    ignored: u64,
+}
+
+const fn bitmap_words(order: usize) -> usize {
+    ((MAX_FRAMES >> order) + 63) / 64
+}
+
+const fn bitmap_offset(order: usize) -> usize {
+    let mut offset = 0;
+    let mut i = 0;
+    while i < order {
+        offset += bitmap_words(i);
+        i += 1;
+    }
+    offset
+}
+
+pub fn order_of(size: PageSize) -> usize {
+    match size {
+        PageSize::Small => 0,
+        PageSize::Medium => 9,
+        PageSize::Large => 18,
+    }
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [0; BITMAP_WORDS],
+            free_blocks: [0; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+            ignored: 0,
+        }
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Bytes of memory above `MAX_PHYS_ADDR` that were left out
+    pub fn ignored(&self) -> u64 {
+        self.ignored
+    }
+
+    #[allow(unused)]
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    /// Hand the physical range `[start, end)` over to the allocator.
+    /// Partial frames at both ends are dropped, as is memory above `MAX_PHYS_ADDR`.
+    pub fn add_range(&mut self, start: u64, end: u64) {
+        self.ignored += end.saturating_sub(start.max(MAX_PHYS_ADDR));
+        let mut start = (start + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let end = end.min(MAX_PHYS_ADDR) & !(FRAME_SIZE - 1);
+        while start < end {
+            let mut order = MAX_ORDER;
+            while start % (FRAME_SIZE << order) != 0 || start + (FRAME_SIZE << order) > end {
+                order -= 1;
+            }
+            self.total_frames += 1 << order;
+            self.free(start, order);
+            start += FRAME_SIZE << order;
+        }
+    }
+
+    /// Allocate a block of `2^order` frames, aligned to its own size.
+    pub fn alloc(&mut self, order: usize) -> Option<u64> {
+        assert!(order <= MAX_ORDER, "invalid order {}", order);
+        let found = (order..=MAX_ORDER).find(|&o| self.free_blocks[o] != 0)?;
+        let mut index = self.find_free(found).expect("buddy bitmap out of sync");
+        self.clear(found, index);
+        // split the block until it has the requested size,
+        // the upper half of every split goes back to the free list
+        for o in (order..found).rev() {
+            index *= 2;
+            self.set(o, index + 1);
+        }
+        self.free_frames -= 1 << order;
+        Some((index << order) as u64 * FRAME_SIZE)
+    }
+
+    /// Return a block allocated by `alloc` with the same order,
+    /// merging it with its buddy as long as the buddy is free.
+    pub fn free(&mut self, addr: u64, order: usize) {
+        assert!(order <= MAX_ORDER, "invalid order {}", order);
+        assert!(
+            addr % (FRAME_SIZE << order) == 0,
+            "addr {:#X} is not aligned to order {}",
+            addr,
+            order
+        );
+        assert!(addr < MAX_PHYS_ADDR, "addr {:#X} out of range", addr);
+        let mut index = (addr / FRAME_SIZE) as usize >> order;
+        let mut cur = order;
+        assert!(!self.test(cur, index), "double free of frame {:#X}", addr);
+        while cur < MAX_ORDER && self.test(cur, index ^ 1) {
+            self.clear(cur, index ^ 1);
+            index >>= 1;
+            cur += 1;
+        }
+        self.set(cur, index);
+        self.free_frames += 1 << order;
+    }
+
+    fn find_free(&self, order: usize) -> Option<usize> {
+        let offset = bitmap_offset(order);
+        for i in 0..bitmap_words(order) {
+            let word = self.bitmap[offset + i];
+            if word != 0 {
+                return Some(i * 64 + word.trailing_zeros() as usize);
+            }
+        }
+        None
+    }
+
+    fn test(&self, order: usize, index: usize) -> bool {
+        let word = self.bitmap[bitmap_offset(order) + index / 64];
+        word & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, order: usize, index: usize) {
+        self.bitmap[bitmap_offset(order) + index / 64] |= 1 << (index % 64);
+        self.free_blocks[order] += 1;
+    }
+
+    fn clear(&mut self, order: usize, index: usize) {
+        self.bitmap[bitmap_offset(order) + index / 64] &= !(1 << (index % 64));
+        self.free_blocks[order] -= 1;
+    }
+}
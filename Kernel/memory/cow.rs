@@ -0,0 +1,43 @@
+use alloc::collections::BTreeMap;
+
+use crate::{interrupts::run_without_interrupt, sync::spin::SpinMutex};
+
+// number of mappings of every frame shared copy-on-write,
+// a frame with a single mapping left is not tracked anymore
+static SHARED: SpinMutex<BTreeMap<u64, usize>> = SpinMutex::new(BTreeMap::new());
+
+/// Count one more mapping of the frame at `addr`
+pub fn share(addr: u64) {
+    run_without_interrupt(|| {
+        *SHARED.lock().entry(addr).or_insert(1) += 1;
+    })
+}
+
+/// Drop one mapping of the frame at `addr`,
+/// returns true if it was the last one and the frame can be freed
+pub fn release(addr: u64) -> bool {
+    run_without_interrupt(|| {
+        let mut shared = SHARED.lock();
+        match shared.get_mut(&addr) {
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    shared.remove(&addr);
+                }
+                false
+            }
+        }
+    })
+}
+
+/// Whether the frame at `addr` is mapped more than once
+pub fn is_shared(addr: u64) -> bool {
+    run_without_interrupt(|| SHARED.lock().contains_key(&addr))
+}
+
+/// Number of frames currently shared
+#[allow(unused)]
+pub fn shared_frames() -> usize {
+    run_without_interrupt(|| SHARED.lock().len())
+}
@@ -1,4 +1,25 @@
-use crate::{MultibootInfo, KERNEL_BASE};
+use crate::{sync::spin::SpinMutex, MultibootInfo, MultibootModule, KERNEL_BASE};
+
+use super::{
+    buddy::{order_of, BuddyAllocator, FRAME_SIZE, MAX_PHYS_ADDR},
+    BOOT_MAP_SIZE,
+};
+
+static FRAMES: SpinMutex<BuddyAllocator> = SpinMutex::new(BuddyAllocator::new());
+
+// memory below 1M is left to BIOS and boot structures
+const LOW_MEMORY_END: u64 = 0x100000;
+
+// refer to https://www.gnu.org/software/grub/manual/multiboot/multiboot.html
+const MULTIBOOT_FLAG_MEM: u32 = 1 << 0;
+const MULTIBOOT_FLAG_CMDLINE: u32 = 1 << 2;
+const MULTIBOOT_FLAG_MODS: u32 = 1 << 3;
+const MULTIBOOT_FLAG_MMAP: u32 = 1 << 6;
+const MULTIBOOT_FLAG_LOADER_NAME: u32 = 1 << 9;
+pub const MULTIBOOT_MEMORY_AVAILABLE: u32 = 1;
+pub const MULTIBOOT_MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+
+const MAX_RESERVED: usize = 32;
 
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub struct Frame {
@@ -8,6 +29,7 @@ pub struct Frame {
 
 #[allow(unused)]
 #[derive(Clone, Copy)]
+#[repr(C, packed)]
 struct Area {
     size: u32,
     addr: u64,
@@ -23,26 +45,37 @@ pub enum PageSize {
     Large = 0x4000_0000,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub used_frames: usize,
+}
+
 #[allow(unused)]
 pub trait FrameAllocator {
-    fn allocate_frame(&mut self) -> Option<Frame>;
+    fn allocate_frame_with_size(&mut self, size: PageSize) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
+
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_frame_with_size(PageSize::Small)
+    }
 }
 
+/// Handle to the global physical frame pool
 pub struct Allocator {
-    areas: AreaIterator,
-    kernel_start: u64,
-    kernel_end: u64,
-    multiboot_start: u64,
-    multiboot_end: u64,
-    cur_area: Area,
-    current_addr: u64,
+    _private: (),
+}
+
+struct ReservedRegions {
+    regions: [(u64, u64); MAX_RESERVED],
+    len: usize,
 }
 
 struct AreaIterator {
     addr: u64,
-    cur: usize,
-    length: usize,
+    offset: u64,
+    length: u64,
 }
 
 impl Frame {
@@ -51,6 +84,14 @@ impl Frame {
     pub fn end_addr(&self) -> u64 {
         self.addr + self.size as u64 - 1
     }
+    pub fn new(addr: u64, size: PageSize) -> Self {
+        assert!(
+            addr % size as u64 == 0,
+            "frame {:#X} is not aligned to its size",
+            addr
+        );
+        Self { addr, size }
+    }
     pub fn new_small_page(addr: u64) -> Self {
         Self {
             addr,
@@ -69,38 +110,167 @@ impl Iterator for AreaIterator {
     type Item = Area;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur >= self.length {
+        if self.offset >= self.length {
             return None;
         }
-        let entry =
-            (self.addr + KERNEL_BASE + core::mem::size_of::<Area>() as u64 * self.cur as u64)
-                as *const Area;
+        let entry = (self.addr + KERNEL_BASE + self.offset) as *const Area;
         let entry = unsafe { *entry };
-        self.cur += 1;
+        // `size` doesn't count the size field itself
+        self.offset += entry.size as u64 + 4;
         Some(entry)
     }
 }
 
+impl ReservedRegions {
+    const fn new() -> Self {
+        Self {
+            regions: [(0, 0); MAX_RESERVED],
+            len: 0,
+        }
+    }
+
+    /// Reserve `start..end`, a region it overlaps or touches grows to cover it.
+    /// With the table full, the closest region grows to it, frames in between included.
+    fn push(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let regions = &mut self.regions[..self.len];
+        let distance = |&(res_start, res_end): &(u64, u64)| {
+            res_start
+                .saturating_sub(end)
+                .max(start.saturating_sub(res_end))
+        };
+        let closest = regions.iter_mut().min_by_key(|region| distance(region));
+        match closest {
+            Some(region) if distance(region) == 0 || self.len == MAX_RESERVED => {
+                *region = (region.0.min(start), region.1.max(end));
+            }
+            _ => {
+                self.regions[self.len] = (start, end);
+                self.len += 1;
+            }
+        }
+    }
+
+    fn as_slice(&self) -> &[(u64, u64)] {
+        &self.regions[..self.len]
+    }
+
+    /// Reserve every structure the boot loader passed to us,
+    /// the kernel may still read them after the allocator is set up
+    fn push_multiboot(&mut self, info: *const MultibootInfo) {
+        let info_start = info as u64 - KERNEL_BASE;
+        self.push(
+            info_start,
+            info_start + core::mem::size_of::<MultibootInfo>() as u64,
+        );
+
+        let info = unsafe { &*info };
+        let flags = info.flags;
+        if flags & MULTIBOOT_FLAG_MMAP != 0 {
+            let mmap_addr = info.mmap_addr as u64;
+            self.push(mmap_addr, mmap_addr + info.mmap_length as u64);
+        }
+        if flags & MULTIBOOT_FLAG_CMDLINE != 0 {
+            self.push_c_string(info.cmdline as u64);
+        }
+        if flags & MULTIBOOT_FLAG_LOADER_NAME != 0 {
+            self.push_c_string(info.boot_loader_name as u64);
+        }
+        if flags & MULTIBOOT_FLAG_MODS != 0 && info.mods_count != 0 {
+            let mods_addr = info.mods_addr as u64;
+            let mods_count = info.mods_count as u64;
+            let mod_size = core::mem::size_of::<MultibootModule>() as u64;
+            self.push(mods_addr, mods_addr + mods_count * mod_size);
+            if mods_addr + mods_count * mod_size > BOOT_MAP_SIZE {
+                log!(
+                    "module list at {:#X} is beyond the boot mapping, the modules are not reserved",
+                    mods_addr
+                );
+                return;
+            }
+            for i in 0..mods_count {
+                let module = unsafe {
+                    *((mods_addr + KERNEL_BASE + i * mod_size) as *const MultibootModule)
+                };
+                self.push(module.mod_start as u64, module.mod_end as u64);
+                if module.string != 0 {
+                    self.push_c_string(module.string as u64);
+                }
+            }
+        }
+    }
+
+    fn push_c_string(&mut self, addr: u64) {
+        // only the boot mapping can be read this early
+        let mut end = addr;
+        while end < BOOT_MAP_SIZE && unsafe { *((end + KERNEL_BASE) as *const u8) } != 0 {
+            end += 1;
+        }
+        if end >= BOOT_MAP_SIZE {
+            log!(
+                "string at {:#X} is beyond the boot mapping, a page of it is reserved",
+                addr
+            );
+            end += FRAME_SIZE;
+        }
+        // keep the terminating nul
+        self.push(addr, end + 1);
+    }
+}
+
 impl Allocator {
     pub fn new(info: *const MultibootInfo, kernel_range: (u64, u64)) -> Self {
-        let (mmap_length, mmap_addr) = unsafe { ((*info).mmap_length, (*info).mmap_addr) };
-        let mut areas = AreaIterator {
-            addr: mmap_addr as u64,
-            cur: 0,
-            length: mmap_length as usize,
-        };
-        let multiboot_start = info as u64;
-        let multiboot_end = multiboot_start + core::mem::size_of::<MultibootInfo>() as u64;
-        let area = areas.next().unwrap();
-        Self {
-            cur_area: area,
-            areas,
-            kernel_start: kernel_range.0,
-            kernel_end: kernel_range.1,
-            multiboot_start,
-            multiboot_end,
-            current_addr: 0x100000,
+        let mut reserved = ReservedRegions::new();
+        reserved.push(0, LOW_MEMORY_END);
+        reserved.push(kernel_range.0, kernel_range.1);
+        reserved.push_multiboot(info);
+
+        let mut frames = FRAMES.lock();
+        assert!(
+            frames.total_frames() == 0,
+            "frame allocator is already initialized"
+        );
+        for_each_area(info, |start, end, typ| {
+            if typ == MULTIBOOT_MEMORY_AVAILABLE {
+                add_free_range(&mut frames, start, end, reserved.as_slice());
+            }
+        });
+        log!(
+            "{} frames available ({} KiB)",
+            frames.free_frames(),
+            frames.free_frames() as u64 * FRAME_SIZE / 1024
+        );
+        if frames.ignored() != 0 {
+            log!(
+                "{} MiB of memory above {:#X} are not used",
+                frames.ignored() / 1024 / 1024,
+                MAX_PHYS_ADDR
+            );
+        }
+
+        Self { _private: () }
+    }
+
+    /// Get another handle to the pool set up by `Allocator::new`
+    pub fn get() -> Self {
+        assert!(
+            FRAMES.lock().total_frames() != 0,
+            "frame allocator is not initialized"
+        );
+        Self { _private: () }
+    }
+
+    /// Allocate `count` physically contiguous frames, aligned to `count`
+    /// rounded up to a power of two. Every frame is released on its own.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<Frame> {
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        let addr = FRAMES.lock().alloc(order)?;
+        for i in count..1 << order {
+            FRAMES.lock().free(addr + i as u64 * FRAME_SIZE, 0);
         }
+        Some(Frame::new_small_page(addr))
     }
 
     pub fn new_frame(&mut self) -> Frame {
@@ -108,49 +278,73 @@ impl Allocator {
     }
 
     pub fn new_frame_with_type(&mut self, typ: PageSize) -> Frame {
-        let mut area = self.cur_area;
-        while self.in_kernel(self.current_addr, self.current_addr + typ as u64)
-            || self.in_multiboot(self.current_addr, self.current_addr + typ as u64)
-        {
-            self.current_addr += typ as u64;
-        }
-        while !area.contain(self.current_addr) {
-            area = self.areas.next().expect("out of physical memory");
-        }
-        self.cur_area = area;
-        let frame = Frame {
-            addr: self.current_addr,
-            size: typ,
-        };
-        self.current_addr += typ as u64;
-
-        frame
+        self.allocate_frame_with_size(typ)
+            .expect("out of physical memory")
     }
 
-    fn in_kernel(&self, start: u64, end: u64) -> bool {
-        !(end < self.kernel_start || start > self.kernel_end)
-    }
-    fn in_multiboot(&self, start: u64, end: u64) -> bool {
-        !(end < self.multiboot_start || start > self.multiboot_end)
+    pub fn stats(&self) -> FrameStats {
+        let frames = FRAMES.lock();
+        FrameStats {
+            total_frames: frames.total_frames(),
+            free_frames: frames.free_frames(),
+            used_frames: frames.total_frames() - frames.free_frames(),
+        }
     }
 }
 
-impl FrameAllocator for Allocator {
-    fn allocate_frame(&mut self) -> Option<Frame> {
-        Some(self.new_frame())
+/// Call `f` with the range and multiboot type of every area in the memory map
+pub fn for_each_area<F>(info: *const MultibootInfo, mut f: F)
+where
+    F: FnMut(u64, u64, u32),
+{
+    let (flags, mmap_length, mmap_addr) =
+        unsafe { ((*info).flags, (*info).mmap_length, (*info).mmap_addr) };
+    if flags & MULTIBOOT_FLAG_MMAP != 0 {
+        let areas = AreaIterator {
+            addr: mmap_addr as u64,
+            offset: 0,
+            length: mmap_length as u64,
+        };
+        for area in areas {
+            f(area.addr, area.addr + area.len, area.typ);
+        }
+    } else {
+        // no memory map, fall back to the upper memory size reported by BIOS
+        assert!(
+            flags & MULTIBOOT_FLAG_MEM != 0,
+            "boot loader didn't provide any memory information"
+        );
+        let mem_upper = unsafe { (*info).mem_upper } as u64 * 1024;
+        f(
+            LOW_MEMORY_END,
+            LOW_MEMORY_END + mem_upper,
+            MULTIBOOT_MEMORY_AVAILABLE,
+        );
     }
+}
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        // TODO
-        // todo!()
+/// Add `[start, end)` to the pool, skipping everything covered by `reserved`
+fn add_free_range(frames: &mut BuddyAllocator, start: u64, end: u64, reserved: &[(u64, u64)]) {
+    if start >= end {
+        return;
+    }
+    for (i, &(res_start, res_end)) in reserved.iter().enumerate() {
+        if start < res_end && res_start < end {
+            add_free_range(frames, start, res_start.max(start), &reserved[i + 1..]);
+            add_free_range(frames, res_end.min(end), end, &reserved[i + 1..]);
+            return;
+        }
     }
+    frames.add_range(start, end);
 }
 
-impl Area {
-    pub fn end_addr(&self) -> u64 {
-        self.addr + self.len - 1
+impl FrameAllocator for Allocator {
+    fn allocate_frame_with_size(&mut self, size: PageSize) -> Option<Frame> {
+        let addr = FRAMES.lock().alloc(order_of(size))?;
+        Some(Frame { addr, size })
     }
-    pub fn contain(&self, addr: u64) -> bool {
-        addr >= self.addr && addr <= self.end_addr()
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        FRAMES.lock().free(frame.addr, order_of(frame.size));
     }
 }
@@ -1,9 +1,28 @@
 use core::ptr::addr_of;
 
+use super::stack::{KernelStack, StackOwner};
+
+// boot stacks, used until the guarded ones are set up by init_stacks
 const STACK_SIZE: usize = 0x2000;
-pub static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub static mut IST_BOOT_STACKS: [[u8; STACK_SIZE]; IST_STACKS.len()] =
+    [[0; STACK_SIZE]; IST_STACKS.len()];
 pub static mut PRIV_TSS_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
+// slots of interrupt_stack_table, each exception gets its own so they can nest
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const DEBUG_IST_INDEX: u16 = 3;
+
+/// Slot, size and name of every IST stack
+const IST_STACKS: [(u16, usize, &str); 4] = [
+    // dumps the registers and the backtrace before it panics
+    (DOUBLE_FAULT_IST_INDEX, 0x4000, "double fault stack"),
+    (NMI_IST_INDEX, 0x2000, "nmi stack"),
+    (MACHINE_CHECK_IST_INDEX, 0x4000, "machine check stack"),
+    (DEBUG_IST_INDEX, 0x2000, "debug stack"),
+];
+
 pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
 static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 
@@ -87,10 +106,10 @@ impl GlobalDescriptorTable {
 
 pub fn init() {
     unsafe {
-        TSS.interrupt_stack_table[0] = {
-            let stack_end = STACK.as_ptr() as *const _ as usize + STACK_SIZE;
-            stack_end as u64
-        };
+        for (slot, &(index, _, _)) in IST_STACKS.iter().enumerate() {
+            let stack_end = IST_BOOT_STACKS[slot].as_ptr() as usize + STACK_SIZE;
+            TSS.interrupt_stack_table[index as usize] = stack_end as u64;
+        }
         TSS.privilege_stack_table[0] = {
             let stack_end = PRIV_TSS_STACK.as_ptr() as *const _ as usize + STACK_SIZE;
             stack_end as u64
@@ -116,3 +135,17 @@ pub fn init() {
         );
     }
 }
+
+/// Move the TSS stacks to guarded ones, so an overflow faults instead of
+/// silently running into whatever is below them
+pub fn init_stacks() {
+    for &(index, size, name) in IST_STACKS.iter() {
+        let stack = KernelStack::new(size, StackOwner::Named(name));
+        unsafe { TSS.interrupt_stack_table[index as usize] = stack.top() };
+        // used for the rest of the kernel's life
+        core::mem::forget(stack);
+    }
+    let privilege = KernelStack::new(STACK_SIZE, StackOwner::Named("privilege stack"));
+    unsafe { TSS.privilege_stack_table[0] = privilege.top() };
+    core::mem::forget(privilege);
+}
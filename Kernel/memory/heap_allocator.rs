@@ -1,63 +1,297 @@
 use core::{
-    alloc::GlobalAlloc,
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
     ptr::null_mut,
-    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{interrupts::run_without_interrupt, sync::spin::SpinMutex};
+
+use super::{
+    frame::{Allocator, FrameAllocator, PageSize},
+    map,
+    page_table::Page,
 };
 
 pub const HEAP_START: usize = 0x1000_0000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_INITIAL_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 0x4000_0000;
+// map at least this much every time the heap runs out of space
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+// every block is a multiple of this, so any leftover can hold a `FreeBlock`
+const BLOCK_GRANULE: usize = size_of::<FreeBlock>();
+
 #[global_allocator]
-static ALLOCATOR: HeapAllocator = HeapAllocator::new(HEAP_START, HEAP_START + HEAP_SIZE);
+static ALLOCATOR: HeapAllocator = HeapAllocator::new(HEAP_START, HEAP_START + HEAP_MAX_SIZE);
 
 pub struct HeapAllocator {
-    #[allow(unused)]
-    heap_start: usize,
-    heap_end: usize,
-    next: AtomicUsize,
+    heap: SpinMutex<Heap>,
+}
+
+/// First-fit allocator over an address ordered list of free blocks.
+/// Adjacent free blocks are merged on free, and the heap maps more
+/// frames behind its end when no block is large enough.
+struct Heap {
+    start: usize,
+    // end of the mapped part of the heap
+    end: usize,
+    limit: usize,
+    // bytes handed out, including the rounding of `block_layout`
+    used: usize,
+    head: *mut FreeBlock,
+}
+unsafe impl Send for Heap {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub start: usize,
+    pub mapped: usize,
+    pub used: usize,
+    pub limit: usize,
+}
+
+#[repr(C, align(16))]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
 }
 
 impl HeapAllocator {
-    pub const fn new(heap_start: usize, heap_end: usize) -> Self {
+    pub const fn new(heap_start: usize, heap_limit: usize) -> Self {
         Self {
-            heap_start,
-            heap_end,
-            next: AtomicUsize::new(heap_start),
+            heap: SpinMutex::new(Heap::new(heap_start, heap_limit)),
         }
     }
 }
 
-unsafe impl GlobalAlloc for HeapAllocator {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+/// Map the initial part of the heap, must be called once the frame allocator is ready
+pub fn init() {
+    run_without_interrupt(|| {
+        let mut heap = ALLOCATOR.heap.lock();
+        assert!(heap.end == heap.start, "heap is already initialized");
+        assert!(heap.grow(HEAP_INITIAL_SIZE), "cannot map the initial heap");
+    });
+}
+
+pub fn stats() -> HeapStats {
+    run_without_interrupt(|| {
+        let heap = ALLOCATOR.heap.lock();
+        HeapStats {
+            start: heap.start,
+            mapped: heap.end - heap.start,
+            used: heap.used,
+            limit: heap.limit,
+        }
+    })
+}
+
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(BLOCK_GRANULE), BLOCK_GRANULE);
+    let align = layout.align().max(BLOCK_GRANULE);
+    (size, align)
+}
+
+impl Heap {
+    const fn new(start: usize, limit: usize) -> Self {
+        Self {
+            start,
+            end: start,
+            limit,
+            used: 0,
+            head: null_mut(),
+        }
+    }
+
+    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
         loop {
-            // load current state of the `next` field
-            // FIXME: the mutex operation seems to be wrong
-            let current_next = self.next.load(Ordering::Acquire);
-            let alloc_start = align_up(current_next, layout.align());
-            let alloc_end = alloc_start.saturating_add(layout.size());
-
-            if alloc_end <= self.heap_end {
-                // update the `next` pointer if it still has the value `current_next`
-                let next_now = self
-                    .next
-                    .compare_exchange(
-                        current_next,
-                        alloc_end,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                    )
-                    .unwrap();
-                if next_now == current_next {
-                    // next address was successfully updated, allocation succeeded
-                    return alloc_start as *mut u8;
-                }
-            } else {
+            if let Some(addr) = self.take(size, align) {
+                return addr as *mut u8;
+            }
+            if !self.grow(size + align) {
                 return null_mut();
             }
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
-        // TODO: don't leak memory
+    /// Carve `size` bytes aligned to `align` out of the first block that fits
+    unsafe fn take(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() {
+            let block_start = cur as usize;
+            let block_end = block_start + (*cur).size;
+            let start = align_up(block_start, align);
+            let end = start.saturating_add(size);
+            if end <= block_end {
+                let mut link = (*cur).next;
+                if end < block_end {
+                    let tail = end as *mut FreeBlock;
+                    tail.write(FreeBlock {
+                        size: block_end - end,
+                        next: link,
+                    });
+                    link = tail;
+                }
+                if start > block_start {
+                    // keep the padding in front as a smaller free block
+                    (*cur).size = start - block_start;
+                    (*cur).next = link;
+                } else {
+                    self.set_next(prev, link);
+                }
+                return Some(start);
+            }
+            prev = cur;
+            cur = (*cur).next;
+        }
+        None
+    }
+
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() && (cur as usize) < addr {
+            prev = cur;
+            cur = (*cur).next;
+        }
+        assert!(
+            (cur.is_null() || addr + size <= cur as usize)
+                && (prev.is_null() || prev as usize + (*prev).size <= addr),
+            "heap corruption, freeing {:#X} overlaps a free block",
+            addr
+        );
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next: cur });
+        if !cur.is_null() && addr + size == cur as usize {
+            (*block).size += (*cur).size;
+            (*block).next = (*cur).next;
+        }
+        if !prev.is_null() && prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            self.set_next(prev, block);
+        }
+    }
+
+    /// Extend the block at `addr` from `old_size` to `new_size`
+    /// using the free block right behind it, if there is one
+    unsafe fn grow_in_place(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let old_end = addr + old_size;
+        let needed = new_size - old_size;
+        if old_end == self.end && !self.grow(needed) {
+            return false;
+        }
+
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() && (cur as usize) < old_end {
+            prev = cur;
+            cur = (*cur).next;
+        }
+        if cur as usize != old_end || (*cur).size < needed {
+            return false;
+        }
+
+        let mut link = (*cur).next;
+        if (*cur).size > needed {
+            let tail = (addr + new_size) as *mut FreeBlock;
+            tail.write(FreeBlock {
+                size: (*cur).size - needed,
+                next: link,
+            });
+            link = tail;
+        }
+        self.set_next(prev, link);
+        true
+    }
+
+    /// Map at least `min_size` more bytes behind the end of the heap
+    fn grow(&mut self, min_size: usize) -> bool {
+        let page_size = PageSize::Small as usize;
+        let size = align_up(min_size.max(HEAP_GROW_SIZE), page_size);
+        let size = size.min(self.limit - self.end);
+        let mut allocator = Allocator::get();
+        let mut mapped = 0;
+        while mapped < size {
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+            let page = Page::new_small_page((self.end + mapped) as u64);
+            map(page, frame, &mut allocator).set_writable(true);
+            mapped += page_size;
+        }
+        if mapped == 0 {
+            return false;
+        }
+
+        let old_end = self.end;
+        self.end += mapped;
+        unsafe { self.free(old_end, mapped) };
+        true
+    }
+
+    unsafe fn set_next(&mut self, prev: *mut FreeBlock, next: *mut FreeBlock) {
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        run_without_interrupt(|| {
+            let mut heap = self.heap.lock();
+            let ptr = heap.alloc(size, align);
+            if !ptr.is_null() {
+                heap.used += size;
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        run_without_interrupt(|| {
+            let mut heap = self.heap.lock();
+            heap.free(ptr as usize, size);
+            heap.used -= size;
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_size, _) = block_layout(layout);
+        let (new_block_size, _) = block_layout(new_layout);
+        let in_place = run_without_interrupt(|| {
+            let mut heap = self.heap.lock();
+            let in_place = if new_block_size <= old_size {
+                if new_block_size < old_size {
+                    heap.free(ptr as usize + new_block_size, old_size - new_block_size);
+                }
+                true
+            } else {
+                heap.grow_in_place(ptr as usize, old_size, new_block_size)
+            };
+            if in_place {
+                heap.used = heap.used - old_size + new_block_size;
+            }
+            in_place
+        });
+        if in_place {
+            return ptr;
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
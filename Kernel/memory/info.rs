@@ -0,0 +1,228 @@
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::MultibootInfo;
+
+use super::{
+    active_table,
+    frame::{for_each_area, Allocator, PageSize},
+    heap_allocator,
+    page_table::{Level4, PageTable, PageTableEntry, RECURSIVE_INDEX},
+};
+
+// kept to report the memory map, the boot loader's structures stay reserved
+static BOOT_INFO: AtomicPtr<MultibootInfo> = AtomicPtr::new(core::ptr::null_mut());
+
+pub fn init(info: *const MultibootInfo) {
+    BOOT_INFO.store(info as *mut _, Ordering::Relaxed);
+}
+
+/// Log the memory map, frame and heap usage and the page tables of the loaded space
+pub fn report() {
+    log!("memory map:");
+    let info = BOOT_INFO.load(Ordering::Relaxed);
+    if !info.is_null() {
+        // bytes per multiboot type 1 to 5, the other types are counted at 0
+        let mut totals = [0u64; 6];
+        for_each_area(info, |start, end, typ| {
+            log!(
+                "  {:#012X}-{:#012X} {:>8} KiB {}",
+                start,
+                end,
+                (end - start) / 1024,
+                area_name(typ)
+            );
+            let index = if (1..=5).contains(&typ) {
+                typ as usize
+            } else {
+                0
+            };
+            totals[index] += end - start;
+        });
+        for (typ, total) in totals.iter().enumerate().filter(|(_, &total)| total != 0) {
+            log!("  {:>10}: {} KiB", area_name(typ as u32), total / 1024);
+        }
+    }
+
+    let frames = Allocator::get().stats();
+    log!(
+        "frames: {} total, {} free, {} used ({} KiB)",
+        frames.total_frames,
+        frames.free_frames,
+        frames.used_frames,
+        frames.used_frames * PageSize::Small as usize / 1024
+    );
+    let heap = heap_allocator::stats();
+    log!(
+        "heap: {} KiB used of {} KiB mapped at {:#X}, limit {} KiB",
+        heap.used / 1024,
+        heap.mapped / 1024,
+        heap.start,
+        heap.limit / 1024
+    );
+    let tables = count_tables(active_table());
+    log!(
+        "page tables: {} frames ({} KiB) in the loaded space",
+        tables,
+        tables * PageSize::Small as usize / 1024
+    );
+}
+
+fn area_name(typ: u32) -> &'static str {
+    match typ {
+        1 => "available",
+        2 => "reserved",
+        3 => "ACPI",
+        4 => "ACPI NVS",
+        5 => "bad",
+        // reserved by the spec, whatever the firmware means with it
+        _ => "other/reserved",
+    }
+}
+
+/// Number of tables reachable from `p4`, itself included
+pub fn count_tables(p4: &PageTable<Level4>) -> usize {
+    let mut count = 1;
+    for i4 in (0..512).filter(|&i| i != RECURSIVE_INDEX) {
+        let p3 = match p4.next_table(i4) {
+            Some(p3) => p3,
+            None => continue,
+        };
+        count += 1;
+        for i3 in 0..512 {
+            let p2 = match p3.next_table(i3) {
+                Some(p2) => p2,
+                None => continue,
+            };
+            count += 1;
+            count += (0..512).filter(|&i2| p2.next_table(i2).is_some()).count();
+        }
+    }
+    count
+}
+
+/// Log the mappings of `p4`, neighbouring pages with the same flags
+/// and contiguous frames are shown as one range.
+/// Flags: w(ritable) u(ser) x(ecutable) g(lobal) n(o cache) c(opy-on-write)
+pub fn dump_mappings(p4: &PageTable<Level4>) {
+    let mut dump = MappingDump { run: None };
+    for_each_mapping(p4, |virt, phys, size, entry| {
+        dump.add(virt, phys, size, entry)
+    });
+    dump.flush();
+}
+
+/// See `dump_mappings`, for the loaded space
+pub fn dump_active() {
+    dump_mappings(active_table());
+}
+
+struct Run {
+    start: u64,
+    end: u64,
+    phys: u64,
+    flags: [u8; 6],
+}
+
+struct MappingDump {
+    run: Option<Run>,
+}
+
+impl MappingDump {
+    fn add(&mut self, virt: u64, phys: u64, size: PageSize, entry: &PageTableEntry) {
+        let flags = entry_flags(entry);
+        if let Some(run) = &mut self.run {
+            if run.end == virt && run.phys + (run.end - run.start) == phys && run.flags == flags {
+                run.end += size as u64;
+                return;
+            }
+        }
+        self.flush();
+        self.run = Some(Run {
+            start: virt,
+            end: virt + size as u64,
+            phys,
+            flags,
+        });
+    }
+
+    fn flush(&mut self) {
+        if let Some(run) = self.run.take() {
+            log!(
+                "  {:#018X}-{:#018X} -> {:#012X} {} {} KiB",
+                run.start,
+                run.end,
+                run.phys,
+                core::str::from_utf8(&run.flags).unwrap(),
+                (run.end - run.start) / 1024
+            );
+        }
+    }
+}
+
+fn entry_flags(entry: &PageTableEntry) -> [u8; 6] {
+    let flag = |set: bool, c: u8| if set { c } else { b'-' };
+    [
+        flag(entry.is_writable(), b'w'),
+        flag(entry.is_user(), b'u'),
+        flag(!entry.is_no_execute(), b'x'),
+        flag(entry.is_global(), b'g'),
+        flag(entry.is_cache_disabled(), b'n'),
+        flag(entry.is_cow(), b'c'),
+    ]
+}
+
+/// Call `f` with the virtual and physical address, size and entry of every page mapped by `p4`
+fn for_each_mapping<F>(p4: &PageTable<Level4>, mut f: F)
+where
+    F: FnMut(u64, u64, PageSize, &PageTableEntry),
+{
+    for i4 in (0..512).filter(|&i| i != RECURSIVE_INDEX) {
+        let p3 = match p4.next_table(i4) {
+            Some(p3) => p3,
+            None => continue,
+        };
+        // addresses of the upper half are sign extended
+        let base = (if i4 >= 256 { 0xffff_0000_0000_0000 } else { 0 }) | (i4 as u64) << 39;
+        for i3 in 0..512 {
+            let addr = base | (i3 as u64) << 30;
+            if p3[i3].is_present() && p3[i3].is_huge() {
+                f(
+                    addr,
+                    p3[i3].frame_addr(PageSize::Large),
+                    PageSize::Large,
+                    &p3[i3],
+                );
+                continue;
+            }
+            let p2 = match p3.next_table(i3) {
+                Some(p2) => p2,
+                None => continue,
+            };
+            for i2 in 0..512 {
+                let addr = addr | (i2 as u64) << 21;
+                if p2[i2].is_present() && p2[i2].is_huge() {
+                    f(
+                        addr,
+                        p2[i2].frame_addr(PageSize::Medium),
+                        PageSize::Medium,
+                        &p2[i2],
+                    );
+                    continue;
+                }
+                let p1 = match p2.next_table(i2) {
+                    Some(p1) => p1,
+                    None => continue,
+                };
+                for i1 in (0..512).filter(|&i| p1[i].is_present()) {
+                    let addr = addr | (i1 as u64) << 12;
+                    f(
+                        addr,
+                        p1[i1].frame_addr(PageSize::Small),
+                        PageSize::Small,
+                        &p1[i1],
+                    );
+                }
+            }
+        }
+    }
+}
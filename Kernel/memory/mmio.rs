@@ -0,0 +1,149 @@
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::arch::instruction::{cpuid, wrmsr};
+
+use super::{
+    frame::{Allocator, Frame, PageSize},
+    map,
+    page_table::Page,
+    tlb, unmap_range_keep_frames,
+    vmalloc::{release, reserve},
+};
+
+const MSR_IA32_PAT: u64 = 0x277;
+// PA0-PA3 keep their reset values (WB, WT, UC-, UC), PA4 becomes WC
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device, in order
+    Uncached,
+    /// Writes may be combined and reordered, for frame buffers
+    WriteCombining,
+    /// Cached like RAM, for firmware data outside the direct map
+    WriteBack,
+}
+
+/// Physical memory of a device mapped into kernel space, unmapped on drop
+pub struct IoMem {
+    start: u64,
+    end: u64,
+    // virtual address of the first byte that was asked for
+    addr: u64,
+    len: usize,
+}
+
+/// One register of an `IoMem`, every access is volatile
+pub struct Register<'a, T> {
+    ptr: *mut T,
+    _mem: PhantomData<&'a IoMem>,
+}
+
+/// Turn PAT entry 4 into write-combining
+pub fn init() {
+    // CPUID.01H:EDX.PAT[bit 16]
+    let (_, _, _, edx) = cpuid(1, 0);
+    if edx & 1 << 16 == 0 {
+        log!("PAT not supported, write-combining falls back to uncached");
+        return;
+    }
+    wrmsr(MSR_IA32_PAT, PAT_VALUE);
+    // nothing used the PAT bit so far, but the old types may still be cached
+    tlb::flush_global();
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Map `len` bytes of device memory at `phys` with the caching of `mode`
+pub fn ioremap(phys: u64, len: usize, mode: CacheMode) -> IoMem {
+    assert!(len > 0, "cannot map an empty range at {:#X}", phys);
+    let page_size = PageSize::Small as u64;
+    let phys_start = phys & !(page_size - 1);
+    let size = (phys + len as u64 - phys_start + page_size - 1) & !(page_size - 1);
+    let start = reserve(size).expect("out of kernel virtual memory");
+
+    let mut allocator = Allocator::get();
+    for offset in (0..size).step_by(page_size as usize) {
+        let entry = map(
+            Page::new_small_page(start + offset),
+            Frame::new_small_page(phys_start + offset),
+            &mut allocator,
+        );
+        entry.set_writable(true);
+        match mode {
+            CacheMode::WriteBack => {}
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => {
+                entry.set_pat(true, PageSize::Small);
+            }
+            _ => {
+                entry.set_cache_disable(true).set_write_through(true);
+            }
+        }
+    }
+    IoMem {
+        start,
+        end: start + size,
+        addr: start + (phys - phys_start),
+        len,
+    }
+}
+
+impl IoMem {
+    /// Virtual address of the physical address passed to `ioremap`
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The register of type `T` at `offset` bytes into the mapping
+    pub fn reg<T: Copy>(&self, offset: usize) -> Register<'_, T> {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.len,
+            "register at {:#X} is outside of the mapping",
+            offset
+        );
+        let ptr = (self.addr + offset as u64) as *mut T;
+        assert!(ptr.is_aligned(), "register at {:#X} is misaligned", offset);
+        Register {
+            ptr,
+            _mem: PhantomData,
+        }
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.reg(offset).read()
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.reg(offset).write(value)
+    }
+}
+
+impl<T: Copy> Register<'_, T> {
+    pub fn read(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.ptr) }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { core::ptr::write_volatile(self.ptr, value) }
+    }
+
+    /// Read the register, change the value with `f` and write it back
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
+}
+
+impl Drop for IoMem {
+    fn drop(&mut self) {
+        unmap_range_keep_frames(self.start, self.end, &mut Allocator::get());
+        release(self.start);
+    }
+}
@@ -1,234 +1,790 @@
-use self::heap_allocator::HEAP_SIZE;
-use self::heap_allocator::HEAP_START;
-
 use self::page_table::Page;
 
-use self::page_table::flush_page_table;
+use self::address_space::is_kernel_address;
 
 use self::frame::Frame;
 use self::frame::FrameAllocator;
 use self::page_table::PageTableEntry;
 
-use self::page_table::P4;
+use self::page_table::{Level1, Level2, Level3, Level4, PageTable, TableLevel, P4};
 
 use self::frame::PageSize;
 
-use self::frame::Allocator;
+use crate::arch::instruction::cpuid;
+use crate::{MultibootInfo, KERNEL_BASE};
 
-use crate::MultibootInfo;
+use self::frame::{for_each_area, MULTIBOOT_MEMORY_ACPI_RECLAIMABLE, MULTIBOOT_MEMORY_AVAILABLE};
 
+pub mod address_space;
+pub mod buddy;
+pub mod cow;
 pub mod frame;
 pub mod gdt;
 pub mod heap_allocator;
+pub mod info;
+pub mod mmio;
 pub mod page_table;
+pub mod slab;
+pub mod stack;
+pub mod tlb;
+pub mod vma;
+pub mod vmalloc;
+
+// all usable physical memory is mapped linearly at this address
+pub const PHYS_MAP_BASE: u64 = 0xffff_8000_0000_0000;
+pub const PHYS_MAP_P4_INDEX: usize = 256;
+const PHYS_MAP_SIZE: u64 = 0x80_0000_0000;
 
-pub fn init<A>(allocator: &mut A)
+pub fn init<A>(info: *const MultibootInfo, allocator: &mut A)
 where
     A: FrameAllocator,
 {
-    // map heap
-    let heap_start_page = Page::new_small_page(HEAP_START as u64);
-    let heap_end_page = Page::new_small_page((HEAP_START + HEAP_SIZE) as u64);
+    info::init(info);
+    tlb::init();
+    // before any address space copies the kernel slots
+    address_space::init_kernel_slots(allocator);
+    init_direct_map(info, allocator);
+    remap_kernel(allocator);
+    vmalloc::init(allocator);
+    mmio::init();
+    heap_allocator::init();
+    gdt::init_stacks();
+}
+
+extern "C" {
+    static text_start: u8;
+    static text_end: u8;
+    static rodata_start: u8;
+    static data_start: u8;
+}
+
+// start.S maps this much memory at KERNEL_BASE with 2M pages
+const BOOT_MAP_SIZE: u64 = 0x40_0000;
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        map(page, allocator.allocate_frame().unwrap(), allocator).set_writable(true);
+/// Replace the boot mapping of the kernel by 4K pages with the permissions
+/// of each section: .text RX, .rodata R, .data and .bss RW.
+/// Everything else in the boot mapping is RW and not executable.
+fn remap_kernel<A>(allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let (text, rodata) = unsafe {
+        (
+            &text_start as *const u8 as u64..&text_end as *const u8 as u64,
+            // read-only sections without a rule end up behind .rodata
+            &rodata_start as *const u8 as u64..&data_start as *const u8 as u64,
+        )
+    };
+    let start = Page::new_small_page(KERNEL_BASE);
+    let end = Page::new_small_page(KERNEL_BASE + BOOT_MAP_SIZE - PageSize::Small as u64);
+    for page in Page::range_inclusive(start, end) {
+        // splits the boot 2M pages on first use
+        let entry = entry_create(active_table(), page, false, allocator);
+        if text.contains(&page.addr) {
+            entry.set_writable(false).set_no_execute(false);
+        } else if rodata.contains(&page.addr) {
+            entry.set_no_execute(true).set_writable(false);
+        } else {
+            entry.set_no_execute(true).set_writable(true);
+        }
+        // the boot mapping is the same in every address space
+        entry.set_global(true);
     }
+    tlb::flush_global();
 }
 
-pub fn read_page() {
-    let cr3: u64;
-    unsafe {
-        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
-    }
-    log!("cr3: {cr3:#X}");
-    let p4 = unsafe { &*P4 };
-    let mut addr = 0;
-    for i in 0..512 {
-        if !p4[i].is_unused() {
-            log!("pml4 entry {} -> physical addr {:#X}", i, p4[i].addr());
-            if i != 510 {
-                let p3 = p4.next_table(i).unwrap();
-                addr &= !(0o777 << 12 + 9 + 9 + 9);
-                addr |= i << 39;
-                for i in 0..512 {
-                    if !p3[i].is_unused() && !p3[i].is_huge() {
-                        log!("  pdpt entry {} -> physical addr {:#X}", i, p3[i].addr());
-                        addr &= !(0o777 << 12 + 9 + 9);
-                        addr |= i << 30;
-                        if i != 510 {
-                            let p2 = p3.next_table(i).unwrap();
-                            for i in 0..512 {
-                                if !p2[i].is_unused() && !p2[i].is_huge() {
-                                    log!("    pd entry {} -> physical addr {:#X}", i, p2[i].addr());
-                                    addr &= !(0o777 << 12 + 9);
-                                    addr |= i << 21;
-                                    if i != 510 {
-                                        let p1 = p2.next_table(i).unwrap();
-                                        for i in 0..512 {
-                                            if !p1[i].is_unused() {
-                                                addr &= !(0o777 << 12);
-                                                addr |= i << 12;
-                                                let mut flags = ['-', '-', '-'];
-                                                if p1[i].is_writable() {
-                                                    flags[0] = 'w';
-                                                }
-                                                if p1[i].is_present() {
-                                                    flags[1] = 'p';
-                                                }
-                                                if p1[i].is_user() {
-                                                    flags[2] = 'u';
-                                                }
-                                                log!(
-                                                    "      {:#X}-{:#X} {}{}{} ->  {:#X}",
-                                                    addr,
-                                                    addr + 0x1000,
-                                                    flags[0],
-                                                    flags[1],
-                                                    flags[2],
-                                                    p1[i].addr()
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+/// Map every RAM area of the boot memory map at `PHYS_MAP_BASE + phys`,
+/// using the largest pages the area's alignment allows
+fn init_direct_map<A>(info: *const MultibootInfo, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    // CPUID.80000001H:EDX.Page1GB
+    let (_, _, _, edx) = cpuid(0x8000_0001, 0);
+    let has_1g_pages = edx & 1 << 26 != 0;
+    let mut mapped = 0;
+    for_each_area(info, |start, end, typ| {
+        if typ != MULTIBOOT_MEMORY_AVAILABLE && typ != MULTIBOOT_MEMORY_ACPI_RECLAIMABLE {
+            return;
+        }
+        let mut addr = start & !(PageSize::Small as u64 - 1);
+        let end = end.min(PHYS_MAP_SIZE);
+        while addr < end {
+            // areas may share a page or overlap, what an earlier one mapped is skipped
+            let hole = match leaf_entry(active_table(), phys_to_virt(addr)) {
+                Ok((_, size)) => {
+                    addr = (addr & !(size as u64 - 1)) + size as u64;
+                    continue;
                 }
-            }
+                Err(hole) => hole,
+            };
+            let size = [PageSize::Large, PageSize::Medium, PageSize::Small]
+                .iter()
+                .copied()
+                .filter(|&size| size != PageSize::Large || has_1g_pages)
+                .find(|&size| {
+                    addr % size as u64 == 0 && addr + size as u64 <= end && size as u64 <= hole
+                })
+                .unwrap_or(PageSize::Small);
+            map(
+                Page::new(phys_to_virt(addr), size),
+                Frame::new(addr, size),
+                allocator,
+            )
+            .set_writable(true);
+            addr += size as u64;
+            mapped += size as u64;
         }
-    }
+    });
+    log!("direct map covers {} MiB", mapped / 1024 / 1024);
+}
+
+/// Address of physical memory `phys` inside the direct map
+pub fn phys_to_virt(phys: u64) -> u64 {
+    assert!(phys < PHYS_MAP_SIZE, "{:#X} is beyond the direct map", phys);
+    PHYS_MAP_BASE + phys
+}
+
+/// Whether the direct map covers `phys..phys + len`, only RAM is in there
+pub fn is_direct_mapped(phys: u64, len: u64) -> bool {
+    let page_size = PageSize::Small as u64;
+    let start = phys & !(page_size - 1);
+    phys + len <= PHYS_MAP_SIZE
+        && (start..phys + len)
+            .step_by(page_size as usize)
+            .all(|addr| lookup(active_table(), phys_to_virt(addr)).is_some())
+}
+
+/// Inverse of `phys_to_virt`, other addresses have to go through `virt_to_physical`
+pub fn virt_to_phys(virt: u64) -> u64 {
+    assert!(
+        virt >= PHYS_MAP_BASE && virt < PHYS_MAP_BASE + PHYS_MAP_SIZE,
+        "{:#X} is not inside the direct map",
+        virt
+    );
+    virt - PHYS_MAP_BASE
 }
 
 pub fn virt_to_physical(virt_addr: u64) -> u64 {
+    let (entry, size) = lookup(active_table(), virt_addr)
+        .unwrap_or_else(|| panic!("address {:#X} is not mapped", virt_addr));
+    entry.frame_addr(size) + (virt_addr & (size as u64 - 1))
+}
+
+/// The table currently loaded in CR3, reached through the recursive slot
+fn active_table() -> &'static mut PageTable<Level4> {
+    unsafe { &mut *P4 }
+}
+
+/// Find the entry of `p4` mapping `virt_addr` and the size of the page it maps
+fn lookup(p4: &PageTable<Level4>, virt_addr: u64) -> Option<(&mut PageTableEntry, PageSize)> {
     assert!(
         virt_addr < 0x0000_8000_0000_0000 || virt_addr >= 0xffff_8000_0000_0000,
         "invalid address: {:#X}",
         virt_addr
     );
 
-    let p4 = unsafe { &*P4 };
     let page = Page::new_small_page(virt_addr);
-    // log!("translate virt_addr {:#X}", virt_addr);
-    let p3 = p4.next_table(page.p4_index()).unwrap();
-    if p3[page.p3_index()].is_huge() {
-        return p3[page.p3_index()].addr() + virt_addr & 0x3fff_ffff;
+    let p3 = p4.next_table_mut(page.p4_index())?;
+    if p3[page.p3_index()].is_present() && p3[page.p3_index()].is_huge() {
+        return Some((&mut p3[page.p3_index()], PageSize::Large));
+    }
+    let p2 = p3.next_table_mut(page.p3_index())?;
+    if p2[page.p2_index()].is_present() && p2[page.p2_index()].is_huge() {
+        return Some((&mut p2[page.p2_index()], PageSize::Medium));
     }
-    let p2 = p3.next_table(page.p3_index()).unwrap();
-    if p2[page.p2_index()].is_huge() {
-        return p2[page.p2_index()].addr() + virt_addr & 0x001f_ffff;
+    let p1 = p2.next_table_mut(page.p2_index())?;
+    if !p1[page.p1_index()].is_present() {
+        return None;
     }
-    let p1 = p2.next_table(page.p2_index()).unwrap();
-    let addr = p1[page.p1_index()].addr();
-    return addr + (virt_addr & 0x0fff);
+    Some((&mut p1[page.p1_index()], PageSize::Small))
 }
 
-pub fn map_user<A>(page: Page, frame: Frame, allocator: &mut A) -> &mut PageTableEntry
+/// Get the entry of `p4` for `page`, creating missing tables on the way
+/// and splitting huge pages larger than `page`
+fn entry_create<'a, A>(
+    p4: &'a mut PageTable<Level4>,
+    page: Page,
+    is_user: bool,
+    allocator: &mut A,
+) -> &'a mut PageTableEntry
+where
+    A: FrameAllocator,
+{
+    let p3 = p4.next_table_create(page.p4_index(), is_user, allocator);
+    if page.size == PageSize::Large {
+        return &mut p3[page.p3_index()];
+    }
+    let p2 = p3.next_table_create(page.p3_index(), is_user, allocator);
+    if page.size == PageSize::Medium {
+        return &mut p2[page.p2_index()];
+    }
+    let p1 = p2.next_table_create(page.p2_index(), is_user, allocator);
+    &mut p1[page.p1_index()]
+}
+
+fn map_page<'a, A>(
+    p4: &'a mut PageTable<Level4>,
+    page: Page,
+    frame: Frame,
+    is_user: bool,
+    allocator: &mut A,
+) -> &'a mut PageTableEntry
 where
     A: FrameAllocator,
 {
     assert!(
-        page.size == PageSize::Small,
-        "UNIMPLEMENTED: only 4kb mapping is support currently"
+        page.size == frame.size,
+        "page {:#X} and frame {:#X} differ in size",
+        page.addr,
+        frame.addr
     );
-    let p4 = unsafe { &mut *P4 };
-    let p3 = p4.next_table_create(page.p4_index(), true, allocator);
-    let p2 = p3.next_table_create(page.p3_index(), true, allocator);
-    let p1 = p2.next_table_create(page.p2_index(), true, allocator);
-
-    assert!(p1[page.p1_index()].is_unused());
-    let entry = p1[page.p1_index()]
+    let entry = entry_create(p4, page, is_user, allocator);
+    assert!(entry.is_unused(), "page {:#X} is already mapped", page.addr);
+    // nothing is executable unless asked for, see `set_no_execute`
+    entry
         .set_addr(frame.addr)
         .set_present(true)
-        .set_user(true);
-
+        .set_no_execute(true);
+    if page.size != PageSize::Small {
+        entry.set_huge(true);
+    }
+    if is_user {
+        entry.set_user(true);
+    } else if is_kernel_address(page.addr) {
+        // kernel tables are shared, so their entries survive address space switches
+        entry.set_global(true);
+    }
+    // the entry was not present before, nothing of it can be cached
     entry
 }
 
+pub fn map_user<A>(page: Page, frame: Frame, allocator: &mut A) -> &mut PageTableEntry
+where
+    A: FrameAllocator,
+{
+    map_page(active_table(), page, frame, true, allocator)
+}
+
 pub fn map<A>(page: Page, frame: Frame, allocator: &mut A) -> &mut PageTableEntry
 where
     A: FrameAllocator,
 {
+    map_page(active_table(), page, frame, false, allocator)
+}
+
+/// Point an already mapped `page` to `frame`, keeping the flags of the old mapping.
+/// A huge page covering `page` is split first, the frame previously backing
+/// `page` is returned so the caller can release it.
+#[allow(unused)]
+pub fn remap<A>(page: Page, frame: Frame, allocator: &mut A) -> (&mut PageTableEntry, Frame)
+where
+    A: FrameAllocator,
+{
+    remap_page(active_table(), page, frame, allocator)
+}
+
+fn remap_page<'a, A>(
+    p4: &'a mut PageTable<Level4>,
+    page: Page,
+    frame: Frame,
+    allocator: &mut A,
+) -> (&'a mut PageTableEntry, Frame)
+where
+    A: FrameAllocator,
+{
+    assert!(page.size == frame.size);
+    let flush = p4.is_active() || is_kernel_address(page.addr);
+    let entry = entry_create(p4, page, false, allocator);
+    assert!(entry.is_present(), "page {:#X} is not mapped", page.addr);
     assert!(
-        page.size == PageSize::Small,
-        "UNIMPLEMENTED: only 4kb mapping is support currently"
+        page.size == PageSize::Small || entry.is_huge(),
+        "page {:#X} is mapped by smaller pages",
+        page.addr
     );
-    let p4 = unsafe { &mut *P4 };
-    let p3 = p4.next_table_create(page.p4_index(), false, allocator);
-    let p2 = p3.next_table_create(page.p3_index(), false, allocator);
-    let p1 = p2.next_table_create(page.p2_index(), false, allocator);
+    let old = Frame {
+        addr: entry.frame_addr(page.size),
+        size: page.size,
+    };
+    // keep the PAT bit of a huge entry
+    entry.set_addr(frame.addr | (entry.addr() - old.addr));
+    if flush {
+        tlb::flush_page(page.addr);
+    }
+    (entry, old)
+}
 
-    assert!(p1[page.p1_index()].is_unused());
-    let entry = p1[page.p1_index()].set_addr(frame.addr).set_present(true);
+/// Unmap `page` and release its frame, only the TLB entry of that page is dropped.
+/// A smaller page inside of a huge page splits the huge page, the rest stays mapped.
+#[allow(dead_code)]
+pub fn unmap<A>(page: Page, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    unmap_page(active_table(), page, allocator)
+}
 
-    entry
+fn unmap_page<A>(p4: &mut PageTable<Level4>, page: Page, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let virt_addr = page.addr;
+    let flush = p4.is_active() || is_kernel_address(virt_addr);
+    let (entry, size) =
+        lookup(p4, virt_addr).unwrap_or_else(|| panic!("address {:#X} is not mapped", virt_addr));
+    assert!(
+        size as u64 >= page.size as u64,
+        "{:#X} is mapped with smaller pages",
+        virt_addr
+    );
+    let entry = if size != page.size {
+        entry_create(p4, page, false, allocator)
+    } else {
+        entry
+    };
+    release_leaf(entry, page.size, allocator);
+    let freed = free_empty_tables(p4, virt_addr, allocator);
+
+    if flush {
+        tlb::flush_page(virt_addr);
+    }
+    if freed && is_kernel_address(virt_addr) {
+        tlb::flush_all_contexts();
+    }
 }
 
-#[allow(dead_code)]
-pub fn unmap<A>(virt_addr: u64, allocator: &mut A)
+/// Unmap every page in `[start, end)` and release their frames.
+/// Holes are skipped a whole table at a time, and tables left empty are freed.
+#[allow(unused)]
+pub fn unmap_range<A>(start: u64, end: u64, allocator: &mut A)
 where
     A: FrameAllocator,
 {
-    // to test if this addr is valid
-    virt_to_physical(virt_addr);
-    let p4 = unsafe { &mut *P4 };
+    unmap_range_in(active_table(), start, end, allocator)
+}
 
-    let page_index = [
-        (virt_addr & 0o777 << 39) >> 39,
-        (virt_addr & 0o777 << 30) >> 30,
-        (virt_addr & 0o777 << 21) >> 21,
-        (virt_addr & 0o777 << 12) >> 12,
-    ];
-    let p1 = p4
-        .next_table_mut(page_index[0] as usize)
-        .and_then(|p3| p3.next_table_mut(page_index[1] as usize))
-        .and_then(|p2| p2.next_table_mut(page_index[2] as usize))
-        .expect("UNIMPLEMENTED: unmap huge page");
+/// Unmap every page in `[start, end)` without releasing their frames,
+/// for mappings of memory the frame allocator doesn't own, like device registers
+pub fn unmap_range_keep_frames<A>(start: u64, end: u64, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    clear_range(active_table(), start, end, false, allocator)
+}
+
+fn unmap_range_in<A>(p4: &mut PageTable<Level4>, start: u64, end: u64, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    clear_range(p4, start, end, true, allocator)
+}
+
+fn clear_range<A>(
+    p4: &mut PageTable<Level4>,
+    start: u64,
+    end: u64,
+    release_frames: bool,
+    allocator: &mut A,
+) where
+    A: FrameAllocator,
+{
+    let small = PageSize::Small as u64;
+    assert!(
+        start % small == 0 && end % small == 0 && start <= end,
+        "invalid range {:#X}..{:#X}",
+        start,
+        end
+    );
+    let kernel = is_kernel_address(start);
+    let flush = p4.is_active() || kernel;
+    let mut freed = false;
+    let mut addr = start;
+    while addr < end {
+        let page = Page::new_small_page(addr);
+        let next = match leaf_entry(p4, addr) {
+            // nothing is mapped in the rest of the table covering `addr`
+            Err(hole) => (addr & !(hole - 1)).saturating_add(hole),
+            Ok((entry, size)) => {
+                let page_start = addr & !(size as u64 - 1);
+                if page_start < start || page_start + size as u64 > end {
+                    // only a part of a huge page is in range
+                    assert!(release_frames, "cannot split the huge page at {:#X}", addr);
+                    unmap_page(p4, page, allocator);
+                    addr += small;
+                    continue;
+                }
+                if release_frames {
+                    release_leaf(entry, size, allocator);
+                } else {
+                    entry.set_unused();
+                }
+                page_start + size as u64
+            }
+        };
+        // check the tables once we are done with them
+        let done = next >= end || next % PageSize::Medium as u64 == 0;
+        if done && free_empty_tables(p4, page.addr, allocator) {
+            freed = true;
+        }
+        addr = next;
+    }
+
+    if flush {
+        tlb::flush_range(start, end);
+    }
+    if freed && kernel {
+        tlb::flush_all_contexts();
+    }
+}
+
+/// The present last level entry for `virt_addr` and the size it maps,
+/// or the size of the region without any mapping around `virt_addr`
+fn leaf_entry(
+    p4: &PageTable<Level4>,
+    virt_addr: u64,
+) -> Result<(&mut PageTableEntry, PageSize), u64> {
+    let page = Page::new_small_page(virt_addr);
+    let p3 = p4
+        .next_table_mut(page.p4_index())
+        .ok_or(Level4::ENTRY_SIZE)?;
+    if p3[page.p3_index()].is_present() && p3[page.p3_index()].is_huge() {
+        return Ok((&mut p3[page.p3_index()], PageSize::Large));
+    }
+    let p2 = p3
+        .next_table_mut(page.p3_index())
+        .ok_or(Level3::ENTRY_SIZE)?;
+    if p2[page.p2_index()].is_present() && p2[page.p2_index()].is_huge() {
+        return Ok((&mut p2[page.p2_index()], PageSize::Medium));
+    }
+    let p1 = p2
+        .next_table_mut(page.p2_index())
+        .ok_or(Level2::ENTRY_SIZE)?;
+    if !p1[page.p1_index()].is_present() {
+        return Err(Level1::ENTRY_SIZE);
+    }
+    Ok((&mut p1[page.p1_index()], PageSize::Small))
+}
 
+fn release_leaf<A>(entry: &mut PageTableEntry, size: PageSize, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
     let frame = Frame {
-        addr: p1[page_index[3] as usize].addr(),
-        size: PageSize::Small,
+        addr: entry.frame_addr(size),
+        size,
     };
-    // TODO: clear p2 p3 p4 if they are empty
-    p1[page_index[3] as usize].set_unused();
+    // a frame shared copy-on-write is freed with its last mapping
+    let last = !entry.is_cow() || cow::release(frame.addr);
+    entry.set_unused();
+    if last {
+        allocator.deallocate_frame(frame);
+    }
+}
 
-    allocator.deallocate_frame(frame);
+/// Free the tables on the way to `virt_addr` that have become empty, bottom up.
+/// The PDPTs of the kernel half stay, every PML4 points to them.
+fn free_empty_tables<A>(p4: &mut PageTable<Level4>, virt_addr: u64, allocator: &mut A) -> bool
+where
+    A: FrameAllocator,
+{
+    let page = Page::new_small_page(virt_addr);
+    let (i4, i3, i2) = (page.p4_index(), page.p3_index(), page.p2_index());
+    let p3 = match p4.next_table_mut(i4) {
+        Some(p3) => p3,
+        None => return false,
+    };
+    let mut freed = false;
+    if let Some(p2) = p3.next_table_mut(i3) {
+        if let Some(p1) = p2.next_table(i2) {
+            if !p1.is_empty() {
+                return false;
+            }
+            let table = p1 as *const _ as u64;
+            release_table(&mut p2[i2], table, allocator);
+            freed = true;
+        }
+        if !p2.is_empty() {
+            return freed;
+        }
+        let table = p2 as *const _ as u64;
+        release_table(&mut p3[i3], table, allocator);
+        freed = true;
+    }
+    if is_kernel_address(virt_addr) || !p3.is_empty() {
+        return freed;
+    }
+    let table = p3 as *const _ as u64;
+    release_table(&mut p4[i4], table, allocator);
+    true
+}
 
-    flush_page_table();
+/// Free the table at `table` that `entry` points to
+fn release_table<A>(entry: &mut PageTableEntry, table: u64, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let frame = Frame::new_small_page(entry.addr());
+    entry.set_unused();
+    allocator.deallocate_frame(frame);
+    // a table of the loaded space is also mapped through the recursive slot
+    tlb::flush_page(table);
 }
 
-#[allow(unused)]
-pub fn test_allocator(info: *const MultibootInfo, kernel_range: (u64, u64)) {
-    let mut allocator = Allocator::new(info, kernel_range);
+#[test_case]
+fn test_allocator() {
+    let mut allocator = frame::Allocator::get();
+    let before = allocator.stats();
     let f = allocator.new_frame();
     assert!(f.size == PageSize::Small);
+    assert!(f.addr % PageSize::Small as u64 == 0);
+    assert!(allocator.stats().free_frames == before.free_frames - 1);
+
+    let huge = allocator.new_frame_with_type(PageSize::Medium);
     assert!(
-        f.addr == (kernel_range.1 & 0xffff_f000) + 0x1000,
+        huge.addr % PageSize::Medium as u64 == 0,
         "wrong address allocation addr: {:#X}",
-        f.addr
+        huge.addr
     );
+    assert!(allocator.stats().free_frames == before.free_frames - 1 - 512);
+
+    // freeing both must coalesce back to the original state
+    allocator.deallocate_frame(huge);
+    allocator.deallocate_frame(f);
+    assert!(allocator.stats().free_frames == before.free_frames);
+    let again = allocator.new_frame();
+    assert!(again.addr == f.addr);
+    allocator.deallocate_frame(again);
 }
 
-#[allow(unused)]
-pub fn test_map<A>(allocator: &mut A)
-where
-    A: FrameAllocator,
-{
+#[test_case]
+fn test_map() {
+    let allocator = &mut frame::Allocator::get();
     let virt_addr = 0xffff_0000;
-    let frame = allocator.allocate_frame().expect("no more frames");
-    let physical_addr = frame.addr;
+    let frame = allocator.new_frame();
     map(Page::new_small_page(virt_addr), frame, allocator).set_writable(true);
-    log!("map virt addr {:#X} to {:#X}", virt_addr, physical_addr);
+    assert!(virt_to_physical(virt_addr + 8) == frame.addr + 8);
+
+    // the new page and the direct map reach the same memory
+    unsafe { *(virt_addr as *mut u64) = 0x1234 };
+    assert!(unsafe { *(phys_to_virt(frame.addr) as *const u64) } == 0x1234);
+
+    unmap(Page::new_small_page(virt_addr), allocator);
+    assert!(lookup(active_table(), virt_addr).is_none());
+}
+
+#[test_case]
+fn test_huge_map() {
+    let allocator = &mut frame::Allocator::get();
+    let virt_addr = 0x4000_0000;
+    let frame = allocator
+        .allocate_frame_with_size(PageSize::Medium)
+        .expect("no more frames");
+    let physical_addr = frame.addr;
+    map(Page::new(virt_addr, PageSize::Medium), frame, allocator).set_writable(true);
+    assert!(virt_to_physical(virt_addr + 0x1234) == physical_addr + 0x1234);
+
+    // unmapping the first 4K piece leaves the rest of the huge page alone
+    unmap(Page::new_small_page(virt_addr), allocator);
+    assert!(lookup(active_table(), virt_addr).is_none());
+    assert!(virt_to_physical(virt_addr + 0x1234) == physical_addr + 0x1234);
+
+    // replacing a 4K piece splits the huge page, the rest stays in place
+    let small = allocator.allocate_frame().expect("no more frames");
+    let (_, old) = remap(Page::new_small_page(virt_addr + 0x1000), small, allocator);
+    assert!(old.addr == physical_addr + 0x1000);
+    assert!(virt_to_physical(virt_addr + 0x1010) == small.addr + 0x10);
+    assert!(virt_to_physical(virt_addr + 0x2010) == physical_addr + 0x2010);
+    allocator.deallocate_frame(old);
+
+    for offset in (0x1000..PageSize::Medium as u64).step_by(PageSize::Small as usize) {
+        unmap(Page::new_small_page(virt_addr + offset), allocator);
+    }
+}
+
+#[test_case]
+fn test_unmap_range() {
+    let allocator = &mut frame::Allocator::get();
+    let before = allocator.stats();
+    // sparse pages in a PML4 slot nothing else uses, every one needs its own tables
+    let base = 0x7f00_0000_0000;
+    let addrs = [
+        base,
+        base + 0x1000,
+        base + 0x4000_0000,
+        base + 0x80_0000_0000 - 0x1000,
+    ];
+    for &addr in addrs.iter() {
+        let frame = allocator.new_frame();
+        map(Page::new_small_page(addr), frame, allocator).set_writable(true);
+    }
+    unmap(Page::new_small_page(addrs[1]), allocator);
+    assert!(lookup(active_table(), addrs[0]).is_some());
+
+    unmap_range(base, base + 0x80_0000_0000, allocator);
+    for &addr in addrs.iter() {
+        assert!(lookup(active_table(), addr).is_none());
+    }
+    assert!(active_table()[Page::new_small_page(base).p4_index()].is_unused());
+    // the emptied tables went back as well
+    assert!(allocator.stats().free_frames == before.free_frames);
+}
 
-    let a = virt_addr as *mut u64;
-    log!("a value {:#X}", unsafe { *a });
+#[test_case]
+fn test_address_space() {
+    let allocator = &mut frame::Allocator::get();
+    let mut space = address_space::AddressSpace::new();
+    let virt_addr = 0x4000_0000;
+    let frame = allocator.new_frame();
+
+    // map into a space that is not loaded
+    space.map_user(Page::new_small_page(virt_addr), frame, allocator);
+    assert!(!space.is_active());
+    assert!(space.translate(virt_addr + 0x10) == Some(frame.addr + 0x10));
+    assert!(lookup(active_table(), virt_addr).is_none());
+
+    // kernel mappings made after the space was created show up in it,
+    // in a slot nothing used at boot too
+    let kernel_frame = allocator.new_frame();
+    for kernel_addr in [KERNEL_BASE + 0x4000_0000, 0xffff_9600_0000_0000] {
+        map(Page::new_small_page(kernel_addr), kernel_frame, allocator);
+        assert!(space.translate(kernel_addr) == Some(kernel_frame.addr));
+        unmap_range_keep_frames(kernel_addr, kernel_addr + 0x1000, allocator);
+        assert!(space.translate(kernel_addr).is_none());
+    }
+    allocator.deallocate_frame(kernel_frame);
+
+    // the copy has its own tables and outlives the original
+    let copy = space.clone();
+    drop(space);
+    assert!(copy.translate(virt_addr) == Some(frame.addr));
+    drop(copy);
+    allocator.deallocate_frame(frame);
+}
+
+#[test_case]
+fn test_demand_paging() {
+    let mut space = address_space::AddressSpace::new();
+    // the slab holding the PML4 stays around
+    let before = frame::Allocator::get().stats();
+    let start = 0x4000_0000;
+    space.add_area(
+        start,
+        start + 0x10_0000,
+        vma::VmaFlags {
+            writable: true,
+            executable: false,
+            user: false,
+        },
+    );
+    assert!(space.translate(start).is_none());
+
+    let boot_table = page_table::active_table_address();
+    space.activate();
     unsafe {
-        *a = 1;
+        // both accesses fault and get a fresh zeroed page
+        *((start + 0x10) as *mut u64) = 42;
+        assert!(*((start + 0x10) as *const u64) == 42);
+        assert!(*((start + 0x8_0000) as *const u64) == 0);
     }
-    log!("a value {:#X}", unsafe { *a });
+    assert!(space.translate(start).is_some());
+    assert!(space.translate(start + 0x1000).is_none());
+
+    // faults outside the area or against its flags are left to the caller
+    assert!(!vma::handle_page_fault(start + 0x10_0000, 0));
+    assert!(!vma::handle_page_fault(start + 0x1000, vma::FAULT_USER));
+    assert!(!vma::handle_page_fault(start + 0x1000, vma::FAULT_FETCH));
+    assert!(space.translate(start + 0x1000).is_none());
+    tlb::switch_to(boot_table, 0);
+
+    // the demand allocated pages and their tables go with the space
+    drop(space);
+    assert!(frame::Allocator::get().stats().free_frames == before.free_frames);
+}
+
+#[test_case]
+fn test_copy_on_write() {
+    let mut space = address_space::AddressSpace::new();
+    let before = frame::Allocator::get().stats();
+    let addr = 0x4000_0000;
+    space.add_area(
+        addr,
+        addr + 0x1000,
+        vma::VmaFlags {
+            writable: true,
+            executable: false,
+            user: false,
+        },
+    );
+    let boot_table = page_table::active_table_address();
+    space.activate();
+    unsafe { *(addr as *mut u64) = 1 };
+
+    let child = space.clone();
+    let frame = space.translate(addr).unwrap();
+    assert!(cow::is_shared(frame));
+    // the write gets a private copy, the child keeps the old frame
+    unsafe { *(addr as *mut u64) = 2 };
+    let copy = space.translate(addr).unwrap();
+    assert!(copy != frame && !cow::is_shared(frame));
+
+    // the last mapping of a frame takes it over instead of copying it
+    tlb::switch_to(boot_table, 0);
+    child.activate();
+    assert!(unsafe { *(addr as *const u64) } == 1);
+    unsafe { *(addr as *mut u64) = 3 };
+    assert!(child.translate(addr) == Some(frame));
+    let (entry, _) = lookup(active_table(), addr).unwrap();
+    assert!(entry.is_writable() && !entry.is_cow());
+    assert!(unsafe { *(phys_to_virt(copy) as *const u64) } == 2);
+
+    // the frames are released with the last mapping of each
+    tlb::switch_to(boot_table, 0);
+    drop(space);
+    drop(child);
+    assert!(frame::Allocator::get().stats().free_frames == before.free_frames);
+}
+
+#[test_case]
+fn test_ioremap() {
+    let allocator = &mut frame::Allocator::get();
+    let before = allocator.stats();
+    // the VGA text buffer, the same memory is reachable through KERNEL_BASE
+    let vga = mmio::ioremap(0xB8000 + 2, 4, mmio::CacheMode::Uncached);
+    let old = vga.read::<u16>(0);
+    vga.write::<u16>(0, 0x0f41);
+    assert!(unsafe { *((KERNEL_BASE + 0xB8002) as *const u16) } == 0x0f41);
+    vga.reg::<u16>(0).modify(|_| old);
+
+    let (entry, _) = lookup(active_table(), vga.addr()).unwrap();
+    assert!(entry.frame_addr(PageSize::Small) == 0xB8000 && entry.is_writable());
+    assert!(entry.is_cache_disabled());
+    let addr = vga.addr();
+    drop(vga);
+    assert!(lookup(active_table(), addr).is_none());
+    // the device frames were not handed to the allocator
+    assert!(allocator.stats().free_frames == before.free_frames);
+}
+
+#[test_case]
+fn test_vmalloc() {
+    let allocator = &mut frame::Allocator::get();
+    let before = allocator.stats();
+    let size = 5 * PageSize::Small as usize;
+    let a = vmalloc::vmalloc(size).unwrap();
+    let b = vmalloc::vmalloc(size).unwrap();
+    let (a_addr, b_addr) = (a.as_ptr() as u64, b.as_ptr() as u64);
+    // one unmapped guard page between the ranges
+    assert!(b_addr >= a_addr + size as u64 + PageSize::Small as u64);
+    assert!(lookup(active_table(), b_addr - PageSize::Small as u64).is_none());
+    unsafe { core::ptr::write_bytes(a.as_ptr(), 0xab, size) };
+    assert!(unsafe { *a.as_ptr().add(size - 1) } == 0xab);
 
-    // unmap(virt_addr, allocator);
-    // log!("hello");
-    // FIXME: expect page fault, but got stuck
-    // log!("a value {:#X}", unsafe { *a });
+    vmalloc::vfree(a);
+    // the freed range is handed out again
+    let c = vmalloc::vmalloc(size).unwrap();
+    assert!(c.as_ptr() as u64 == a_addr);
+    vmalloc::vfree(c);
+    vmalloc::vfree(b);
+    // unlike ioremap, the frames behind the ranges belong to them
+    assert!(allocator.stats().free_frames == before.free_frames);
 }
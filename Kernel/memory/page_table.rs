@@ -1,15 +1,26 @@
 use core::{
     marker::PhantomData,
     ops::{Index, IndexMut},
-    ptr::addr_of_mut,
 };
 
+use crate::arch::instruction::read_cr3;
+
 use super::{
     frame::{FrameAllocator, PageSize},
-    virt_to_physical,
+    phys_to_virt, tlb, virt_to_physical,
 };
 
 pub const P4: *mut PageTable<Level4> = 0o177777_776_776_776_776_0000 as *mut _;
+pub const RECURSIVE_INDEX: usize = 510;
+// tables of the loaded address space show up here through the recursive slot
+const RECURSIVE_START: u64 = 0xffff_ff00_0000_0000;
+const RECURSIVE_END: u64 = 0xffff_ff80_0000_0000;
+
+const NO_EXECUTE: u64 = 1 << 63;
+const PAT_SMALL: u64 = 1 << 7;
+const PAT_HUGE: u64 = 1 << 12;
+// bits 9-11 are ignored by the cpu
+const COPY_ON_WRITE: u64 = 1 << 9;
 
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub struct Page {
@@ -27,7 +38,10 @@ pub struct PageTable<L: TableLevel> {
     level: PhantomData<L>,
 }
 
-pub trait TableLevel {}
+pub trait TableLevel {
+    // size of the memory one entry of this level covers
+    const ENTRY_SIZE: u64;
+}
 pub trait MappingTable: HierarchicalLevel {}
 pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
@@ -36,10 +50,18 @@ pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
-impl TableLevel for Level4 {}
-impl TableLevel for Level3 {}
-impl TableLevel for Level2 {}
-impl TableLevel for Level1 {}
+impl TableLevel for Level4 {
+    const ENTRY_SIZE: u64 = 0x80_0000_0000;
+}
+impl TableLevel for Level3 {
+    const ENTRY_SIZE: u64 = PageSize::Large as u64;
+}
+impl TableLevel for Level2 {
+    const ENTRY_SIZE: u64 = PageSize::Medium as u64;
+}
+impl TableLevel for Level1 {
+    const ENTRY_SIZE: u64 = PageSize::Small as u64;
+}
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -97,6 +119,11 @@ impl PageTableEntry {
         self.0 & 1 << 1 != 0
     }
     pub fn set_writable(&mut self, flag: bool) -> &mut Self {
+        assert!(
+            !flag || self.is_no_execute(),
+            "W^X: page {:#X} is executable and can't be writable",
+            self.addr()
+        );
         if flag {
             self.0 |= 1 << 1;
         } else {
@@ -116,6 +143,72 @@ impl PageTableEntry {
         }
         self
     }
+    pub fn is_no_execute(&self) -> bool {
+        self.0 & NO_EXECUTE != 0
+    }
+    /// Entries are either writable or executable, never both
+    pub fn set_no_execute(&mut self, flag: bool) -> &mut Self {
+        assert!(
+            flag || !self.is_writable(),
+            "W^X: page {:#X} is writable and can't be executable",
+            self.addr()
+        );
+        self.set_flag(NO_EXECUTE, flag)
+    }
+    pub fn is_global(&self) -> bool {
+        self.0 & 1 << 8 != 0
+    }
+    pub fn set_global(&mut self, flag: bool) -> &mut Self {
+        self.set_flag(1 << 8, flag)
+    }
+    #[allow(unused)]
+    pub fn set_write_through(&mut self, flag: bool) -> &mut Self {
+        self.set_flag(1 << 3, flag)
+    }
+    pub fn is_cache_disabled(&self) -> bool {
+        self.0 & 1 << 4 != 0
+    }
+    #[allow(unused)]
+    pub fn set_cache_disable(&mut self, flag: bool) -> &mut Self {
+        self.set_flag(1 << 4, flag)
+    }
+    /// The PAT bit of a 4K entry shares its position with the huge bit
+    /// of the upper levels, so the size of the mapped page has to be known
+    #[allow(unused)]
+    pub fn set_pat(&mut self, flag: bool, size: PageSize) -> &mut Self {
+        match size {
+            PageSize::Small => self.set_flag(PAT_SMALL, flag),
+            _ => self.set_flag(PAT_HUGE, flag),
+        }
+    }
+    /// Address of the frame mapped by a leaf entry of `size`,
+    /// the PAT bit of huge entries sits in the address field
+    pub fn frame_addr(&self, size: PageSize) -> u64 {
+        self.addr() & !(size as u64 - 1)
+    }
+    /// Software bit marking a page shared copy-on-write
+    pub fn is_cow(&self) -> bool {
+        self.0 & COPY_ON_WRITE != 0
+    }
+    pub fn set_cow(&mut self, flag: bool) -> &mut Self {
+        self.set_flag(COPY_ON_WRITE, flag)
+    }
+    fn set_flag(&mut self, mask: u64, flag: bool) -> &mut Self {
+        if flag {
+            self.0 |= mask;
+        } else {
+            self.0 &= !mask;
+        }
+        self
+    }
+    /// Entry pointing to a lower level table, the permissions of a mapping
+    /// are decided by its last level entry
+    pub fn table(addr: u64, is_user: bool) -> Self {
+        let mut entry = PageTableEntry(0);
+        entry.set_addr(addr).set_present(true).set_user(is_user);
+        entry.0 |= 1 << 1;
+        entry
+    }
 }
 
 impl<L> Index<usize> for PageTable<L>
@@ -148,12 +241,21 @@ where
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
+
     fn next_table_address(&self, index: usize) -> Option<u64> {
         assert!(index < 512);
         if self[index].is_present() && !self[index].is_huge() {
             let table_address = self as *const _ as u64;
-            let index = index as u64;
-            Some((table_address << 9) | (index << 12) | (1 << 48))
+            if (RECURSIVE_START..RECURSIVE_END).contains(&table_address) {
+                let index = index as u64;
+                Some((table_address << 9) | (index << 12) | (1 << 48))
+            } else {
+                // tables of other address spaces are reached through the direct map
+                Some(phys_to_virt(self[index].addr()))
+            }
         } else {
             None
         }
@@ -193,25 +295,12 @@ where
             core::arch::asm!("mov cr3, {}", in(reg) phys_addr, options(nomem, nostack));
         }
     }
-}
-unsafe fn alloc_page_table() -> *mut PageTable<Level4> {
-    static mut TABLE: PageTable<Level4> = PageTable {
-        entries: [PageTableEntry(0); 512],
-        level: PhantomData,
-    };
-    addr_of_mut!(TABLE) as *mut PageTable<Level4>
-}
-pub fn kernel_page_table() -> *mut PageTable<Level4> {
-    let p4 = unsafe { &*P4 };
-    let new_p4 = unsafe { alloc_page_table() };
-    let p4_addr_phys = virt_to_physical(new_p4 as u64);
-    let p = unsafe { &mut *new_p4 };
-    // NOTE: 510 must be the table's own address!
-    p.entries[510] = PageTableEntry(p4_addr_phys | 3);
-    p.entries[511] = p4.entries[511];
-    p.entries[32] = p4.entries[32];
-
-    new_p4
+
+    /// Whether this table is the one loaded in CR3
+    pub fn is_active(&self) -> bool {
+        let virt_addr = self as *const _ as u64;
+        virt_addr == P4 as u64 || virt_to_physical(virt_addr) == active_table_address()
+    }
 }
 impl<L> PageTable<L>
 where
@@ -240,26 +329,59 @@ where
     where
         A: FrameAllocator,
     {
+        if self[index].is_present() && self[index].is_huge() {
+            self.split_huge(index, allocator);
+        }
         if self.next_table(index).is_none() {
-            assert!(
-                !self[index].is_huge(),
-                "UNIMPLEMENTED: mapping to huge pages"
-            );
             let frame = allocator.allocate_frame().expect("no frame available");
-            self.entries[index]
-                .set_addr(frame.addr)
-                .set_present(true)
-                .set_writable(true);
-            if is_user {
-                self.entries[index].set_user(true);
-            }
+            self.entries[index] = PageTableEntry::table(frame.addr, is_user);
             self.next_table_mut(index).unwrap().reset();
         }
         self.next_table_mut(index).unwrap()
     }
+
+    /// Replace the huge page at `index` by a table of smaller pages
+    /// mapping the same memory with the same flags
+    pub fn split_huge<A>(&mut self, index: usize, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let huge = self[index];
+        assert!(huge.is_present() && huge.is_huge());
+        let child_size = L::NextLevel::ENTRY_SIZE;
+        let frame = allocator.allocate_frame().expect("no frame available");
+        // the new table has to be complete before it replaces the huge page,
+        // the code doing the split may be running inside of that page
+        let table = unsafe { &mut *(phys_to_virt(frame.addr) as *mut PageTable<L::NextLevel>) };
+        let pat = huge.0 & PAT_HUGE != 0;
+        let base = huge.addr() & !PAT_HUGE;
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            *entry = huge;
+            entry.set_addr(base + i as u64 * child_size);
+            if child_size == PageSize::Small as u64 {
+                // the PAT bit moves to where the huge bit was
+                entry.set_huge(false);
+                entry.set_flag(PAT_SMALL, pat);
+            } else {
+                entry.set_flag(PAT_HUGE, pat);
+            }
+        }
+        self.entries[index] = PageTableEntry::table(frame.addr, huge.is_user());
+        // the huge page may be global, and its address isn't known here
+        tlb::flush_global();
+    }
 }
 
 impl Page {
+    pub fn new(addr: u64, size: PageSize) -> Self {
+        assert!(
+            addr % size as u64 == 0,
+            "page {:#X} is not aligned to its size",
+            addr
+        );
+        Self { addr, size }
+    }
+
     pub fn new_small_page(addr: u64) -> Self {
         Self {
             addr,
@@ -317,10 +439,8 @@ impl Iterator for PageIter {
     }
 }
 
-pub fn flush_page_table() {
-    let mut cr3: u64;
-    unsafe {
-        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
-        core::arch::asm!("mov cr3, {}", in(reg) cr3, options(nomem, nostack, preserves_flags));
-    }
+/// Physical address of the loaded PML4
+pub fn active_table_address() -> u64 {
+    // the low bits hold the PCID
+    read_cr3() & 0x000f_ffff_ffff_f000
 }
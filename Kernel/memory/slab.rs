@@ -0,0 +1,324 @@
+use core::{
+    alloc::{AllocError, Layout},
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::{interrupts::run_without_interrupt, sync::spin::SpinMutex};
+
+use super::{
+    frame::{Allocator, Frame, FrameAllocator, PageSize},
+    phys_to_virt, virt_to_phys,
+};
+
+const MAX_SLAB_SIZE: usize = 0x10000;
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+const MAX_CACHES: usize = 16;
+static CACHES: SpinMutex<[Option<&'static dyn CacheInfo>; MAX_CACHES]> =
+    SpinMutex::new([None; MAX_CACHES]);
+static CACHE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub type SlabBox<T> = Box<T, &'static SlabCache<T>>;
+
+/// Object cache handing out fixed-size slots for values of type `T`
+pub struct SlabCache<T> {
+    name: &'static str,
+    inner: SpinMutex<SlabList>,
+    // the cache never owns a `T`, it only hands out memory for one
+    _marker: PhantomData<fn() -> T>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+trait CacheInfo: Sync {
+    fn stats(&self) -> SlabStats;
+}
+
+struct SlabList {
+    // slabs with at least one free object, full slabs are unlinked
+    partial: *mut Slab,
+    // at most one completely free slab is kept around
+    empty: usize,
+    registered: bool,
+    slabs: usize,
+    in_use: usize,
+    allocs: usize,
+    frees: usize,
+}
+unsafe impl Send for SlabList {}
+
+// lives at the start of every slab, objects follow it
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+impl<T> SlabCache<T> {
+    const OBJECT_SIZE: usize = align_up_const(
+        max(size_of::<T>(), size_of::<FreeObject>()),
+        Self::OBJECT_ALIGN,
+    );
+    const OBJECT_ALIGN: usize = max(align_of::<T>(), align_of::<FreeObject>());
+    const HEADER_SIZE: usize = align_up_const(size_of::<Slab>(), Self::OBJECT_ALIGN);
+    const SLAB_SIZE: usize = slab_size(Self::HEADER_SIZE, Self::OBJECT_SIZE);
+    const OBJECTS_PER_SLAB: usize = (Self::SLAB_SIZE - Self::HEADER_SIZE) / Self::OBJECT_SIZE;
+
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            inner: SpinMutex::new(SlabList {
+                partial: null_mut(),
+                empty: 0,
+                registered: false,
+                slabs: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Allocate an uninitialized slot
+    pub fn alloc(&'static self) -> Option<NonNull<T>> {
+        assert!(
+            Self::OBJECTS_PER_SLAB > 0,
+            "object of cache {} doesn't fit into a slab",
+            self.name
+        );
+        run_without_interrupt(|| {
+            let mut list = self.inner.lock();
+            if !list.registered {
+                list.registered = true;
+                register(self);
+            }
+            if list.partial.is_null() {
+                let slab = Self::new_slab()?;
+                unsafe { list.push(slab) };
+                list.slabs += 1;
+                list.empty += 1;
+            }
+            unsafe {
+                let slab = list.partial;
+                if (*slab).in_use == 0 {
+                    list.empty -= 1;
+                }
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).in_use += 1;
+                if (*slab).free.is_null() {
+                    list.remove(slab);
+                }
+                list.in_use += 1;
+                list.allocs += 1;
+                Some(NonNull::new_unchecked(object as *mut T))
+            }
+        })
+    }
+
+    /// Return a slot to the cache, the value is not dropped
+    ///
+    /// `ptr` must come from `alloc` of the same cache
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        let addr = ptr.as_ptr() as usize;
+        // slabs are aligned to their size, so the header is found by aligning down
+        let slab = (addr & !(Self::SLAB_SIZE - 1)) as *mut Slab;
+        assert!(
+            (addr - slab as usize - Self::HEADER_SIZE) % Self::OBJECT_SIZE == 0,
+            "{:#X} is not an object of cache {}",
+            addr,
+            self.name
+        );
+        run_without_interrupt(|| {
+            let mut list = self.inner.lock();
+            let object = addr as *mut FreeObject;
+            if (*slab).free.is_null() {
+                // the slab was full, it has room again
+                list.push(slab);
+            }
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            list.in_use -= 1;
+            list.frees += 1;
+            if (*slab).in_use == 0 {
+                if list.empty == 0 {
+                    list.empty += 1;
+                } else {
+                    list.remove(slab);
+                    list.slabs -= 1;
+                    Self::release_slab(slab);
+                }
+            }
+        });
+    }
+
+    /// Move `value` into a slot of this cache
+    pub fn boxed(&'static self, value: T) -> SlabBox<T> {
+        Box::new_in(value, self)
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let list = self.inner.lock();
+        SlabStats {
+            name: self.name,
+            object_size: Self::OBJECT_SIZE,
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: list.slabs,
+            objects_in_use: list.in_use,
+            allocs: list.allocs,
+            frees: list.frees,
+        }
+    }
+
+    fn new_slab() -> Option<*mut Slab> {
+        let frames = Self::SLAB_SIZE / PageSize::Small as usize;
+        let frame = Allocator::get().allocate_contiguous(frames)?;
+        let start = phys_to_virt(frame.addr);
+
+        let slab = start as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                next: null_mut(),
+                prev: null_mut(),
+                free: null_mut(),
+                in_use: 0,
+            });
+            // chain the objects from the back, so the first one is handed out first
+            for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+                let object =
+                    (start as usize + Self::HEADER_SIZE + i * Self::OBJECT_SIZE) as *mut FreeObject;
+                (*object).next = (*slab).free;
+                (*slab).free = object;
+            }
+        }
+        Some(slab)
+    }
+
+    fn release_slab(slab: *mut Slab) {
+        let mut allocator = Allocator::get();
+        let start = virt_to_phys(slab as u64);
+        for offset in (0..Self::SLAB_SIZE).step_by(PageSize::Small as usize) {
+            allocator.deallocate_frame(Frame::new_small_page(start + offset as u64));
+        }
+    }
+}
+
+impl<T> CacheInfo for SlabCache<T> {
+    fn stats(&self) -> SlabStats {
+        SlabCache::stats(self)
+    }
+}
+
+unsafe impl<T> core::alloc::Allocator for &'static SlabCache<T> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > SlabCache::<T>::OBJECT_SIZE
+            || layout.align() > SlabCache::<T>::OBJECT_ALIGN
+        {
+            return Err(AllocError);
+        }
+        let ptr = SlabCache::alloc(*self).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(
+            ptr.cast(),
+            SlabCache::<T>::OBJECT_SIZE,
+        ))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        self.free(ptr.cast());
+    }
+}
+
+impl SlabList {
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).next = null_mut();
+        (*slab).prev = null_mut();
+    }
+}
+
+fn register<T>(cache: &'static SlabCache<T>) {
+    let index = CACHE_COUNT.fetch_add(1, Ordering::Relaxed);
+    if index < MAX_CACHES {
+        CACHES.lock()[index] = Some(cache);
+    } else {
+        log!("too many slab caches, {} won't be reported", cache.name);
+    }
+}
+
+/// Write the usage of every slab cache to the serial log
+#[allow(unused)]
+pub fn dump_caches() {
+    let caches = CACHES.lock();
+    log!("slab caches:");
+    for cache in caches.iter().flatten() {
+        let stats = cache.stats();
+        log!(
+            "  {:<12} obj {:>5}B  {:>3}/slab  slabs {:>4}  in use {:>6}  allocs {:>8}  frees {:>8}",
+            stats.name,
+            stats.object_size,
+            stats.objects_per_slab,
+            stats.slabs,
+            stats.objects_in_use,
+            stats.allocs,
+            stats.frees
+        );
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up_const(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Smallest power of two number of pages holding enough objects
+const fn slab_size(header_size: usize, object_size: usize) -> usize {
+    let mut size = PageSize::Small as usize;
+    while size < MAX_SLAB_SIZE && (size - header_size) / object_size < MIN_OBJECTS_PER_SLAB {
+        size *= 2;
+    }
+    size
+}
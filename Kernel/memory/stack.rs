@@ -0,0 +1,75 @@
+use core::fmt;
+
+use alloc::collections::BTreeMap;
+
+use crate::{interrupts::run_without_interrupt, sync::spin::SpinMutex};
+
+use super::{
+    frame::PageSize,
+    vmalloc::{vfree, vmalloc},
+};
+
+// bottom of each stack -> its owner
+static STACKS: SpinMutex<BTreeMap<u64, StackOwner>> = SpinMutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug)]
+pub enum StackOwner {
+    Task(u64),
+    Named(&'static str),
+}
+
+/// A stack in the vmalloc region, the unmapped page below it catches overflows
+pub struct KernelStack {
+    bottom: u64,
+    size: u64,
+}
+
+impl fmt::Display for StackOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackOwner::Task(id) => write!(f, "task {}", id),
+            StackOwner::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl KernelStack {
+    pub fn new(size: usize, owner: StackOwner) -> Self {
+        let page_size = PageSize::Small as u64;
+        let size = (size as u64 + page_size - 1) & !(page_size - 1);
+        let bottom = vmalloc(size as usize)
+            .expect("no memory for stack")
+            .as_ptr() as u64;
+        run_without_interrupt(|| STACKS.lock().insert(bottom, owner));
+        Self { bottom, size }
+    }
+
+    /// Address right above the stack, the initial stack pointer
+    pub fn top(&self) -> u64 {
+        self.bottom + self.size
+    }
+
+    pub fn bottom(&self) -> u64 {
+        self.bottom
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        run_without_interrupt(|| STACKS.lock().remove(&self.bottom));
+        vfree(core::ptr::NonNull::new(self.bottom as *mut u8).unwrap());
+    }
+}
+
+/// The owner of the stack whose guard page contains `addr`
+pub fn guard_page_owner(addr: u64) -> Option<StackOwner> {
+    // this runs in fault handlers, which may have interrupted a stack being created
+    // or dropped. The list can't be read then, the fault is reported as a plain one.
+    let stacks = STACKS.try_lock()?;
+    let (&bottom, &owner) = stacks.range(addr.checked_add(1)?..).next()?;
+    if addr >= bottom - PageSize::Small as u64 {
+        Some(owner)
+    } else {
+        None
+    }
+}
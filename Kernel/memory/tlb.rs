@@ -0,0 +1,132 @@
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use crate::{
+    arch::instruction::{cpuid, invlpg, invpcid, read_cr3, read_cr4, write_cr3, write_cr4},
+    interrupts::run_without_interrupt,
+    sync::spin::SpinMutex,
+};
+
+use super::frame::PageSize;
+
+const CR4_PGE: u64 = 1 << 7;
+const CR4_PCIDE: u64 = 1 << 17;
+// keep the TLB entries of the new PCID when loading CR3
+const CR3_NO_FLUSH: u64 = 1 << 63;
+const MAX_PCID: usize = 0x1000;
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+const INVPCID_ALL_NON_GLOBAL: u64 = 3;
+// flushing more pages than this one by one is slower than dropping the whole TLB
+const FLUSH_RANGE_LIMIT: u64 = 32;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+// table that last ran with each PCID, the TLB may still hold its entries
+static PCID_OWNER: SpinMutex<[u64; MAX_PCID]> = SpinMutex::new([0; MAX_PCID]);
+
+/// Turn on PCIDs if the CPU supports them together with INVPCID
+pub fn init() {
+    // CPUID.01H:ECX.PCID[bit 17], CPUID.(EAX=07H,ECX=0):EBX.INVPCID[bit 10]
+    let (_, _, ecx, _) = cpuid(1, 0);
+    let (_, ebx, _, _) = cpuid(7, 0);
+    if ecx & 1 << 17 == 0 || ebx & 1 << 10 == 0 {
+        log!("PCID not supported");
+        return;
+    }
+    // CR4.PCIDE can only be set while the loaded PCID is 0
+    assert!(read_cr3() & 0xfff == 0);
+    write_cr4(read_cr4() | CR4_PCIDE);
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    log!("PCID enabled");
+}
+
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// PCID for a new address space, 0 stays with the boot table
+pub fn alloc_pcid() -> u16 {
+    if !pcid_enabled() {
+        return 0;
+    }
+    let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed) % MAX_PCID as u16;
+    if pcid == 0 {
+        NEXT_PCID.fetch_add(1, Ordering::Relaxed) % MAX_PCID as u16
+    } else {
+        pcid
+    }
+}
+
+/// Load the PML4 at `table` tagged with `pcid`.
+/// The entries cached for `pcid` are kept if `table` was the last one using it.
+pub fn switch_to(table: u64, pcid: u16) {
+    if !pcid_enabled() {
+        write_cr3(table);
+        return;
+    }
+    run_without_interrupt(|| {
+        let mut owners = PCID_OWNER.lock();
+        let keep = owners[pcid as usize] == table;
+        owners[pcid as usize] = table;
+        let no_flush = if keep { CR3_NO_FLUSH } else { 0 };
+        write_cr3(table | pcid as u64 | no_flush);
+    });
+}
+
+/// Drop the entries of one page of the loaded table and global pages
+pub fn flush_page(addr: u64) {
+    invlpg(addr);
+}
+
+/// Drop the entries of every page in `[start, end)`
+pub fn flush_range(start: u64, end: u64) {
+    let page_size = PageSize::Small as u64;
+    if (end - start) / page_size > FLUSH_RANGE_LIMIT {
+        flush_all();
+        return;
+    }
+    for addr in (start & !(page_size - 1)..end).step_by(page_size as usize) {
+        invlpg(addr);
+    }
+}
+
+/// Drop the non-global entries of the loaded table
+pub fn flush_all() {
+    write_cr3(read_cr3());
+}
+
+/// Forget that `table` used `pcid`, a table reusing its frame must not see its entries
+pub fn release_pcid(table: u64, pcid: u16) {
+    if !pcid_enabled() {
+        return;
+    }
+    run_without_interrupt(|| {
+        let mut owners = PCID_OWNER.lock();
+        if owners[pcid as usize] == table {
+            owners[pcid as usize] = 0;
+        }
+    });
+}
+
+/// Drop every entry, including global pages and all PCIDs
+pub fn flush_global() {
+    let cr4 = read_cr4();
+    write_cr4(cr4 & !CR4_PGE);
+    write_cr4(cr4);
+}
+
+/// Drop the entries cached for `pcid` while its table is not loaded
+pub fn flush_pcid(pcid: u16) {
+    if pcid_enabled() {
+        invpcid(INVPCID_SINGLE_CONTEXT, pcid as u64, 0);
+    }
+}
+
+/// Drop the non-global entries and cached tables of every PCID,
+/// needed when a table shared by all address spaces is freed
+pub fn flush_all_contexts() {
+    if pcid_enabled() {
+        invpcid(INVPCID_ALL_NON_GLOBAL, 0, 0);
+    } else {
+        flush_all();
+    }
+}
@@ -0,0 +1,189 @@
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::collections::BTreeMap;
+
+use crate::sync::spin::SpinMutex;
+
+use super::{
+    active_table, cow,
+    frame::{Allocator, FrameAllocator, PageSize},
+    lookup, map_page,
+    page_table::Page,
+    phys_to_virt, tlb,
+};
+
+// page fault error code bits
+pub const FAULT_PRESENT: u64 = 1;
+pub const FAULT_WRITE: u64 = 1 << 1;
+pub const FAULT_USER: u64 = 1 << 2;
+pub const FAULT_FETCH: u64 = 1 << 4;
+
+// areas of the loaded address space, set when a space is activated
+static ACTIVE_AREAS: AtomicPtr<SpinMutex<VmaList>> = AtomicPtr::new(null_mut());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmaFlags {
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+}
+
+/// A range of virtual memory backed by zeroed frames, which are
+/// only allocated and mapped when a page is touched for the first time
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub flags: VmaFlags,
+}
+
+/// Areas of one address space, ordered by their start
+#[derive(Clone, Default)]
+pub struct VmaList {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, vma: Vma) {
+        let page_size = PageSize::Small as u64;
+        assert!(
+            vma.start < vma.end && vma.start % page_size == 0 && vma.end % page_size == 0,
+            "invalid area {:#X}..{:#X}",
+            vma.start,
+            vma.end
+        );
+        let overlaps = self
+            .areas
+            .range(..vma.end)
+            .next_back()
+            .map_or(false, |(_, other)| other.end > vma.start);
+        assert!(
+            !overlaps,
+            "area {:#X}..{:#X} overlaps another area",
+            vma.start, vma.end
+        );
+        self.areas.insert(vma.start, vma);
+    }
+
+    pub fn remove(&mut self, start: u64) -> Option<Vma> {
+        self.areas.remove(&start)
+    }
+
+    /// The area containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
+
+/// Make `areas` the ones the fault handler looks at
+pub fn set_active_areas(areas: &SpinMutex<VmaList>) {
+    ACTIVE_AREAS.store(areas as *const _ as *mut _, Ordering::Relaxed);
+}
+
+/// Stop looking at `areas` if they are the active ones, they are about to go away
+pub fn clear_active_areas(areas: &SpinMutex<VmaList>) {
+    let _ = ACTIVE_AREAS.compare_exchange(
+        areas as *const _ as *mut _,
+        null_mut(),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+}
+
+/// Try to resolve a page fault at `addr` in the loaded address space.
+/// A page of an area that was never touched is backed by a zeroed frame,
+/// a write to a copy-on-write page gets a private copy of it.
+/// The faulting instruction can be restarted if this returns true.
+pub fn handle_page_fault(addr: u64, error_code: u64) -> bool {
+    let areas = ACTIVE_AREAS.load(Ordering::Relaxed);
+    if areas.is_null() {
+        return false;
+    }
+    let vma = match unsafe { (*areas).lock().find(addr) } {
+        Some(vma) => *vma,
+        None => return false,
+    };
+    let allowed = (error_code & FAULT_WRITE == 0 || vma.flags.writable)
+        && (error_code & FAULT_FETCH == 0 || vma.flags.executable)
+        && (error_code & FAULT_USER == 0 || vma.flags.user);
+    if !allowed {
+        return false;
+    }
+    if error_code & FAULT_PRESENT != 0 {
+        return error_code & FAULT_WRITE != 0 && copy_on_write(addr);
+    }
+
+    let mut allocator = Allocator::get();
+    let frame = match allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.addr) as *mut u8,
+            0,
+            PageSize::Small as usize,
+        )
+    };
+    let page = Page::new_small_page(addr & !(PageSize::Small as u64 - 1));
+    let entry = map_page(active_table(), page, frame, vma.flags.user, &mut allocator);
+    entry.set_writable(vma.flags.writable);
+    if vma.flags.executable {
+        entry.set_no_execute(false);
+    }
+    true
+}
+
+/// Give the loaded space its own writable copy of the shared page at `addr`
+fn copy_on_write(addr: u64) -> bool {
+    let (entry, size) = match lookup(active_table(), addr) {
+        Some(res) => res,
+        None => return false,
+    };
+    if !entry.is_cow() || size != PageSize::Small {
+        return false;
+    }
+    let old = entry.addr();
+    // the last mapping of a frame can just take it over
+    if cow::is_shared(old) {
+        let frame = match Allocator::get().allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(old) as *const u8,
+                phys_to_virt(frame.addr) as *mut u8,
+                PageSize::Small as usize,
+            )
+        };
+        entry.set_addr(frame.addr);
+        cow::release(old);
+    }
+    entry.set_cow(false).set_writable(true);
+    tlb::flush_page(addr);
+    true
+}
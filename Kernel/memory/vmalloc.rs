@@ -0,0 +1,97 @@
+use core::ptr::NonNull;
+
+use alloc::collections::BTreeMap;
+
+use crate::{interrupts::run_without_interrupt, sync::spin::SpinMutex};
+
+use super::{
+    active_table,
+    frame::{Allocator, FrameAllocator, PageSize},
+    map,
+    page_table::{Level4, Page, TableLevel},
+    unmap_range,
+};
+
+// kernel virtual memory handed out by `reserve`, one PML4 slot.
+// The heap keeps its fixed place, the ranges are tracked on the heap.
+pub const VMALLOC_START: u64 = 0xffff_c000_0000_0000;
+pub const VMALLOC_END: u64 = VMALLOC_START + Level4::ENTRY_SIZE;
+const GUARD_SIZE: u64 = PageSize::Small as u64;
+
+// ranges in use, start of the guard page -> end of the range
+static RANGES: SpinMutex<BTreeMap<u64, u64>> = SpinMutex::new(BTreeMap::new());
+
+/// Create the table of the region, so every address space shares it
+pub fn init<A>(allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let page = Page::new_small_page(VMALLOC_START);
+    active_table().next_table_create(page.p4_index(), false, allocator);
+}
+
+/// Reserve `size` bytes of kernel virtual memory, nothing is mapped yet.
+/// The page below every range is left unmapped, so running off the start
+/// of a range faults instead of hitting its neighbour.
+pub fn reserve(size: u64) -> Option<u64> {
+    assert!(size > 0, "cannot reserve an empty range");
+    let page_size = PageSize::Small as u64;
+    let size = ((size + page_size - 1) & !(page_size - 1)) + GUARD_SIZE;
+    run_without_interrupt(|| {
+        let mut ranges = RANGES.lock();
+        // first fit
+        let mut start = VMALLOC_START;
+        for (&used_start, &used_end) in ranges.iter() {
+            if used_start - start >= size {
+                break;
+            }
+            start = used_end;
+        }
+        if VMALLOC_END - start < size {
+            return None;
+        }
+        ranges.insert(start, start + size);
+        Some(start + GUARD_SIZE)
+    })
+}
+
+/// Give back the range starting at `start`, its pages must be unmapped already
+pub fn release(start: u64) {
+    run_without_interrupt(|| RANGES.lock().remove(&(start - GUARD_SIZE)))
+        .unwrap_or_else(|| panic!("no range starts at {:#X}", start));
+}
+
+/// End of the range starting at `start`
+pub fn range_end(start: u64) -> Option<u64> {
+    run_without_interrupt(|| RANGES.lock().get(&(start - GUARD_SIZE)).copied())
+}
+
+/// Virtually contiguous memory backed by frames allocated one by one,
+/// for large buffers that don't need to be physically contiguous
+pub fn vmalloc(size: usize) -> Option<NonNull<u8>> {
+    let start = reserve(size as u64)?;
+    let end = range_end(start).unwrap();
+    let mut allocator = Allocator::get();
+    let mut addr = start;
+    while addr < end {
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                unmap_range(start, addr, &mut allocator);
+                release(start);
+                return None;
+            }
+        };
+        map(Page::new_small_page(addr), frame, &mut allocator).set_writable(true);
+        addr += PageSize::Small as u64;
+    }
+    NonNull::new(start as *mut u8)
+}
+
+/// Release memory returned by `vmalloc`
+pub fn vfree(ptr: NonNull<u8>) {
+    let start = ptr.as_ptr() as u64;
+    let end = range_end(start).unwrap_or_else(|| panic!("{:#X} was not vmalloc'ed", start));
+    unmap_range(start, end, &mut Allocator::get());
+    release(start);
+}
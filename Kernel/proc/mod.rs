@@ -1,29 +1,42 @@
-use alloc::vec;
-use alloc::vec::Vec;
+use core::mem::size_of;
 
 use crate::hlt;
 use crate::memory::gdt::{CS_SEL_USER, DS_SEL_USER};
 
-use crate::memory::map_user;
+use crate::memory::address_space::AddressSpace;
 // use crate::memory::gdt::set_usermode_segs;
 use crate::{
     arch::instruction::{rdmsr, wrmsr},
     memory::{
-        frame::{Frame, FrameAllocator},
-        page_table::{flush_page_table, kernel_page_table, Page},
-        read_page, virt_to_physical,
+        frame::{Allocator, Frame, FrameAllocator},
+        info,
+        page_table::Page,
+        virt_to_physical,
+        vma::VmaFlags,
     },
 };
 
 pub mod sheduler;
 mod task;
+pub mod workqueue;
+
+use self::{sheduler::SCHEDULAR, task::InterruptFrame};
 
 const MSR_STAR: u64 = 0xC000_0081;
 const MSR_LSTAR: u64 = 0xC000_0082;
 const MSR_FMASK: u64 = 0xC000_0084;
-const MSR_IA32_EFER: u64 = 0xC000_0081;
+const MSR_IA32_EFER: u64 = 0xC000_0080;
 const MSR_STAR_VALUE: u64 = 0x23_0010_0000_0000;
 
+const USER_CODE_BASE: u64 = 0x40_0000;
+const USER_STACK_START: u64 = 0x80_0000;
+const USER_STACK_END: u64 = 0x80_5000;
+
+// same number as on linux
+pub const SYS_FORK: u64 = 57;
+// logs `memory::info::report` and the caller's mappings, returns the free frames
+pub const SYS_MEMINFO: u64 = 0x100;
+
 #[repr(C)]
 struct SyscallFrame {
     rax: u64,
@@ -143,13 +156,12 @@ extern "C" fn handle_syscall() {
             call x64_handle_syscall
             cli
 
-            pop rax
+            add rsp, 8
             pop rdi
             pop rsi
             pop rdx
             pop r10
 
-        mov rax, rbx
         mov rsp, rbp
         pop r15
         pop r14
@@ -166,6 +178,7 @@ extern "C" fn handle_syscall() {
     }
 }
 
+// the value of rax returned by `x64_handle_syscall` is handed back to user mode
 #[no_mangle]
 fn x64_handle_syscall(frame: *mut SyscallFrame) -> u64 {
     let rax = unsafe { (*frame).rax };
@@ -173,8 +186,49 @@ fn x64_handle_syscall(frame: *mut SyscallFrame) -> u64 {
     let rsi = unsafe { (*frame).rsi };
     let rdx = unsafe { (*frame).rdx };
     let r10 = unsafe { (*frame).r10 };
-    log!("syscall {:x} {:x} {:x} {:x} {:x}", rax, rdi, rsi, rdx, r10);
-    0
+    match rax {
+        SYS_FORK => SCHEDULAR.fork_current(user_registers(frame)),
+        SYS_MEMINFO => {
+            info::report();
+            info::dump_active();
+            Allocator::get().stats().free_frames as u64
+        }
+        _ => {
+            log!("syscall {:x} {:x} {:x} {:x} {:x}", rax, rdi, rsi, rdx, r10);
+            0
+        }
+    }
+}
+
+/// Registers of the user program at the time of the syscall,
+/// as saved by `handle_syscall` on the user stack
+fn user_registers(frame: *mut SyscallFrame) -> InterruptFrame {
+    // r15, r14, r13, r12, rbx, rbp, r11 and rcx are above the scratch space
+    let saved = (frame as usize + size_of::<SyscallFrame>() + 0x400) as *const u64;
+    let saved = unsafe { core::slice::from_raw_parts(saved, 8) };
+    let f = unsafe { &*frame };
+    InterruptFrame {
+        r15: saved[0] as usize,
+        r14: saved[1] as usize,
+        r13: saved[2] as usize,
+        r12: saved[3] as usize,
+        rbx: saved[4] as usize,
+        rbp: saved[5] as usize,
+        r11: saved[6] as usize,
+        rcx: saved[7] as usize,
+        r10: f.r10 as usize,
+        rdx: f.rdx as usize,
+        rsi: f.rsi as usize,
+        rdi: f.rdi as usize,
+        // the child sees 0 as result of the syscall
+        rax: 0,
+        rip: saved[7] as usize,
+        cs: CS_SEL_USER as usize,
+        rflags: saved[6] as usize,
+        rsp: saved.as_ptr() as usize + 8 * 8,
+        ss: DS_SEL_USER as usize,
+        ..Default::default()
+    }
 }
 
 pub fn exec<A>(user_space_fn_in_kernel: u64, allocator: &mut A)
@@ -185,51 +239,58 @@ where
     let user_space_fn_phys = virt_to_physical(user_space_fn_in_kernel);
     let page_phys_start = (user_space_fn_phys >> 12) << 12;
     let fn_page_offset = user_space_fn_phys - page_phys_start;
-    let user_space_fn_virt_base = 0x40_0000;
-    let user_space_fn_virt = user_space_fn_virt_base + fn_page_offset;
+    let user_space_fn_virt = USER_CODE_BASE + fn_page_offset;
     log!(
         "mapping {:#X} to {:#X}, size: {:#X}",
         page_phys_start,
-        user_space_fn_virt_base,
+        USER_CODE_BASE,
         fn_page_offset
     );
-    let stack_space: Vec<u8> = vec![0; 1000];
-    let ptr = stack_space.as_ptr() as *const u8 as u64;
-    let stack_space_phys = virt_to_physical(ptr);
-    let stack_page = Page::new_small_page(0x80_0000);
-    let stack_phys_frame = Frame::new_small_page(stack_space_phys);
-
-    let table = unsafe { &*kernel_page_table() };
-    table.enable();
-    let user_space_page = Page::new_small_page(user_space_fn_virt_base);
-    let phys_frame = Frame::new_small_page(page_phys_start);
-    for i in 0..5 {
-        map_user(
-            user_space_page.offset(0x1000 * i),
-            phys_frame.offset(0x1000 * i),
-            allocator,
-        )
-        .set_present(true);
-    }
-
-    for i in 0..5 {
-        map_user(
-            stack_page.offset(0x1000 * i),
-            stack_phys_frame.offset(0x1000 * i),
-            allocator,
-        )
-        .set_present(true)
-        .set_writable(true);
-    }
 
-    read_page();
+    // the mappings are prepared before the new space is loaded
+    let space = user_space(page_phys_start, allocator);
+    space.activate();
+    space.dump();
 
-    jump_to_user_mode(user_space_fn_virt, 0x80_5000);
-    // prevent rust from freeing stack early
-    drop(stack_space);
+    jump_to_user_mode(user_space_fn_virt, USER_STACK_END);
+    // the program keeps running in this space
+    core::mem::forget(space);
     hlt();
 }
 
+/// Space of a program whose code is at `code_phys`. The code is mapped
+/// read-only and shared as is with a fork, the stack is an area of the space,
+/// so a fork gets it copy-on-write.
+fn user_space<A>(code_phys: u64, allocator: &mut A) -> AddressSpace
+where
+    A: FrameAllocator,
+{
+    let mut space = AddressSpace::new();
+    let user_space_page = Page::new_small_page(USER_CODE_BASE);
+    let phys_frame = Frame::new_small_page(code_phys);
+    for i in 0..5 {
+        space
+            .map_user(
+                user_space_page.offset(0x1000 * i),
+                phys_frame.offset(0x1000 * i),
+                allocator,
+            )
+            .set_present(true)
+            .set_no_execute(false);
+    }
+    // filled with zeroed pages on first touch
+    space.add_area(
+        USER_STACK_START,
+        USER_STACK_END,
+        VmaFlags {
+            writable: true,
+            executable: false,
+            user: true,
+        },
+    );
+    space
+}
+
 pub fn init_syscalls() {
     log!("init syscallls");
     wrmsr(MSR_STAR, MSR_STAR_VALUE);
@@ -250,7 +311,6 @@ pub fn jump_to_user_mode(code: u64, stack_end: u64) {
             "mov ds, {0:x}", in(reg) DS_SEL_USER, options(nostack, preserves_flags)
         );
     }
-    flush_page_table();
     // unsafe { *(0x80_0fff as *mut u8) = 1 };
     // loop {}
     unsafe {
@@ -270,3 +330,27 @@ pub fn jump_to_user_mode(code: u64, stack_end: u64) {
         core::arch::asm!("iretq")
     }
 }
+
+#[test_case]
+fn test_fork_stack() {
+    use crate::memory::{page_table::active_table_address, phys_to_virt, tlb};
+
+    let allocator = &mut Allocator::get();
+    let code = virt_to_physical(user_space_prog_1 as *const () as u64) & !0xfff;
+    let parent = user_space(code, allocator);
+    let boot_table = active_table_address();
+    let stack = USER_STACK_END - 8;
+    parent.activate();
+    unsafe { *(stack as *mut u64) = 1 };
+
+    let child = parent.clone();
+    assert!(child.translate(stack) == parent.translate(stack));
+    // the first write after the fork moves the parent to its own stack page
+    unsafe { *(stack as *mut u64) = 2 };
+    let frame = child.translate(stack).unwrap();
+    assert!(parent.translate(stack) != Some(frame));
+    assert!(unsafe { *(phys_to_virt(frame) as *const u64) } == 1);
+    // the code stays shared
+    assert!(child.translate(USER_CODE_BASE) == parent.translate(USER_CODE_BASE));
+    tlb::switch_to(boot_table, 0);
+}
@@ -1,87 +1,291 @@
-use core::{arch, cell::Cell};
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
 
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
 
 use crate::{
     fs::test_ide_read,
-    hlt,
-    interrupts::enable,
-    sync::{lazy::Lazy, spin::SpinMutex},
+    interrupts::{disable, enable, enable_and_halt, run_without_interrupt, softirq::in_softirq},
+    sync::{lazy::Lazy, preempt::preemptible, spin::SpinMutex},
+    time::{restart_tick, stop_tick, timer::add_timer},
 };
 
-use super::task::{x86_context_switch, X86Task};
+use super::{
+    task::{x86_context_switch, x86_start, InterruptFrame, TaskBox, TaskState, X86Task},
+    workqueue::worker,
+};
 
 pub static SCHEDULAR: Lazy<Arc<Scheduler>> = Lazy::new(|| Arc::new(Scheduler::new()));
+// ids below are taken by the tasks created with the scheduler, ids are never reused
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(5);
+pub const IDLE_TASK_ID: u64 = 1;
+pub const WORKER_TASK_ID: u64 = 4;
+
+// ticks a task runs before the next ready one gets the CPU
+const TIME_SLICE: u32 = 5;
+
+// kept outside the scheduler, interrupt handlers must not be the ones creating it
+static STARTED: AtomicBool = AtomicBool::new(false);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+static SLICE_LEFT: AtomicU32 = AtomicU32::new(TIME_SLICE);
 
 pub struct Scheduler {
-    queue: Arc<SpinMutex<VecDeque<Arc<X86Task>>>>,
-    preempt_task: Arc<X86Task>,
-    current_task: Arc<Cell<Arc<X86Task>>>,
+    // only locked with interrupts disabled, interrupt handlers wake tasks up
+    run_queue: SpinMutex<RunQueue>,
+    current_task: Arc<Cell<TaskBox>>,
 }
 unsafe impl Sync for Scheduler {}
 unsafe impl Send for Scheduler {}
 
+struct RunQueue {
+    // in the order they get the CPU
+    ready: VecDeque<TaskBox>,
+    blocked: BTreeMap<u64, TaskBox>,
+    // dropped by the next `schedule`, the last switch left their stacks
+    zombies: Vec<TaskBox>,
+    // parked here while other tasks run, it never waits in `ready`
+    idle: Option<TaskBox>,
+}
+
 impl Scheduler {
     pub fn new() -> Self {
-        let init_task = Arc::new(X86Task::new_kernel(kernel_1 as *const () as u64, 0));
-        let task2 = Arc::new(X86Task::new_kernel(kernel_2 as *const () as u64, 3));
-        let idle_task = Arc::new(X86Task::new_kernel(idle as *const () as u64, 1));
-        let preempt_task = Arc::new(X86Task::new_kernel(preempt as *const () as u64, 2));
-        let mut deque = VecDeque::new();
-        deque.push_back(init_task);
-        deque.push_back(task2);
-        let queue = Arc::new(SpinMutex::new(deque));
+        let idle_task = X86Task::new_kernel(idle as *const () as u64, IDLE_TASK_ID).boxed();
+        let worker_task = X86Task::new_kernel(worker as *const () as u64, WORKER_TASK_ID).boxed();
+        let mut ready = VecDeque::new();
+        #[cfg(feature = "demo_tasks")]
+        {
+            ready.push_back(X86Task::new_kernel(kernel_1 as *const () as u64, 0).boxed());
+            ready.push_back(X86Task::new_kernel(kernel_2 as *const () as u64, 3).boxed());
+        }
+        ready.push_back(worker_task);
+        let run_queue = SpinMutex::new(RunQueue {
+            ready,
+            blocked: BTreeMap::new(),
+            zombies: Vec::new(),
+            idle: None,
+        });
         Self {
-            queue,
-            preempt_task,
+            run_queue,
+            // replaced by the first task in `start`
             current_task: Arc::new(Cell::new(idle_task)),
         }
     }
 
-    pub fn preempt(&self) {
-        let current = unsafe { &*self.current_task.as_ref().as_ptr() };
-        x86_context_switch(current.get_mut(), self.preempt_task.as_ref())
+    /// The running task. Only dereferenced with interrupts disabled or by the
+    /// task itself, the references must not outlive a switch.
+    fn current(&self) -> *mut X86Task {
+        unsafe { &mut **self.current_task.as_ptr() as *mut X86Task }
     }
-}
 
-fn preempt() {
-    loop {
-        let sched = &*SCHEDULAR;
-        let mut queue = sched.queue.lock();
-        let t = queue.pop_front();
-        if let Some(task) = t {
-            let prev = sched.current_task.replace(task.clone());
-            queue.push_back(prev);
+    /// Run the queued tasks, the caller's context is given up for good
+    pub fn start(&self) -> ! {
+        disable();
+        let mut queue = self.run_queue.lock();
+        if let Some(first) = queue.ready.pop_front() {
+            queue.idle = Some(self.current_task.replace(first));
+        }
+        drop(queue);
+        let first = unsafe { &mut *self.current() };
+        first.set_state(TaskState::Running);
+        SLICE_LEFT.store(TIME_SLICE, Ordering::Relaxed);
+        STARTED.store(true, Ordering::Relaxed);
+        log!("scheduler started with task {}", first.id());
+        x86_start(first)
+    }
+
+    /// Give the CPU to the next ready task, or to the idle task if the current
+    /// one can't go on. A running task goes to the back of the queue.
+    pub fn schedule(&self) {
+        assert!(preemptible(), "scheduling while holding a spin lock");
+        run_without_interrupt(|| {
+            NEED_RESCHED.store(false, Ordering::Relaxed);
+            let mut queue = self.run_queue.lock();
+            // their last switch is over, nothing runs on their stacks
+            queue.zombies.clear();
 
+            let next = match queue.ready.pop_front() {
+                Some(next) => next,
+                None if unsafe { (*self.current()).state() } == TaskState::Running => {
+                    SLICE_LEFT.store(TIME_SLICE, Ordering::Relaxed);
+                    return;
+                }
+                None => queue.idle.take().expect("idle task is running already"),
+            };
+            // the box keeps the task at the same address after being moved into the cell
+            let next_task = &*next as *const X86Task;
+            let mut prev = self.current_task.replace(next);
+            let prev_task = &mut *prev as *mut X86Task;
+            let was_idle = prev.id() == IDLE_TASK_ID;
+            match prev.state() {
+                _ if was_idle => {
+                    prev.set_state(TaskState::Ready);
+                    queue.idle = Some(prev);
+                }
+                // woken up again before it switched away
+                TaskState::Running | TaskState::Ready => {
+                    prev.set_state(TaskState::Ready);
+                    queue.ready.push_back(prev);
+                }
+                TaskState::Blocked => {
+                    let id = prev.id();
+                    assert!(
+                        queue.blocked.insert(id, prev).is_none(),
+                        "two blocked tasks with id {}",
+                        id
+                    );
+                }
+                TaskState::Zombie => queue.zombies.push(prev),
+            }
             drop(queue);
 
-            x86_context_switch(sched.preempt_task.as_ref().get_mut(), task.as_ref())
-        } else {
-            panic!("cannot find task in scheduler queue")
+            unsafe { (*self.current()).set_state(TaskState::Running) };
+            SLICE_LEFT.store(TIME_SLICE, Ordering::Relaxed);
+            if was_idle {
+                // the idle task stopped it
+                restart_tick();
+            }
+            x86_context_switch(unsafe { &mut *prev_task }, unsafe { &*next_task });
+        });
+    }
+
+    /// Let the other ready tasks run first
+    pub fn yield_now(&self) {
+        self.schedule();
+    }
+
+    /// Stop running the current task until `wake_up`. Called with interrupts
+    /// disabled after checking the condition, a wakeup can't get lost in between.
+    pub fn block_current(&self) {
+        run_without_interrupt(|| {
+            unsafe { (*self.current()).set_state(TaskState::Blocked) };
+            self.schedule();
+        });
+    }
+
+    /// Make the blocked task `id` ready, from any context
+    pub fn wake_up(&self, id: u64) {
+        run_without_interrupt(|| {
+            let mut queue = self.run_queue.lock();
+            let current = unsafe { &mut *self.current() };
+            if let Some(mut task) = queue.blocked.remove(&id) {
+                task.set_state(TaskState::Ready);
+                queue.ready.push_back(task);
+            } else if current.id() == id && current.state() == TaskState::Blocked {
+                current.set_state(TaskState::Ready);
+            }
+            // the idle task gives way as soon as the interrupt returns
+            if current.id() == IDLE_TASK_ID && !queue.ready.is_empty() {
+                NEED_RESCHED.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Block the current task for at least `duration`
+    pub fn sleep(&self, duration: Duration) {
+        fn wake(id: usize) {
+            SCHEDULAR.wake_up(id as u64);
         }
+        run_without_interrupt(|| {
+            let current = unsafe { &mut *self.current() };
+            current.set_state(TaskState::Blocked);
+            add_timer(duration, wake, current.id() as usize);
+            self.schedule();
+        });
+    }
+
+    fn has_ready(&self) -> bool {
+        run_without_interrupt(|| !self.run_queue.lock().ready.is_empty())
+    }
+
+    /// Queue a copy of the running task, which resumes in user mode with `regs`.
+    /// Returns the id of the copy.
+    pub fn fork_current(&self, regs: InterruptFrame) -> u64 {
+        let current = unsafe { &*self.current() };
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+        let child = current.fork(regs, id).boxed();
+        run_without_interrupt(|| self.run_queue.lock().ready.push_back(child));
+        log!("task {} forked into {}", current.id(), id);
+        id
+    }
+
+    /// Stop the running task for good, it is dropped once the next task is picked
+    pub fn exit_current(&self) -> ! {
+        disable();
+        let current = unsafe { &mut *self.current() };
+        log!("task {} exited", current.id());
+        current.set_state(TaskState::Zombie);
+        self.schedule();
+        unreachable!("exited task was scheduled again");
     }
 }
 
+pub fn is_started() -> bool {
+    STARTED.load(Ordering::Relaxed)
+}
+
+/// Account a timer tick to the running task, its time slice may run out
+pub fn scheduler_tick() {
+    if !is_started() {
+        return;
+    }
+    if SLICE_LEFT.load(Ordering::Relaxed) <= 1 {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    } else {
+        SLICE_LEFT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Switch tasks on the way out of an interrupt if the time slice ran out or the
+/// idle task has to give way. Nothing happens while the interrupted code holds
+/// a spin lock or runs softirqs, they switch at their next interrupt.
+pub fn preempt_on_irq_exit() {
+    if is_started() && NEED_RESCHED.load(Ordering::Relaxed) && preemptible() && !in_softirq() {
+        SCHEDULAR.schedule();
+    }
+}
+
+// gives way after every round, then exits
+#[cfg(feature = "demo_tasks")]
 fn kernel_1() {
-    loop {
+    for _ in 0..5 {
         for _ in 0..1000000 {
-            unsafe { arch::asm!("nop") };
+            unsafe { core::arch::asm!("nop") };
         }
         log!("kernel1");
+        SCHEDULAR.yield_now();
     }
+    SCHEDULAR.exit_current();
 }
+// blocked between rounds, the idle task runs when nothing else is ready
+#[cfg(feature = "demo_tasks")]
 fn kernel_2() {
     loop {
         for _ in 0..1000000 {
-            unsafe { arch::asm!("nop") };
+            unsafe { core::arch::asm!("nop") };
         }
         log!("2222kernel2222");
+        SCHEDULAR.sleep(Duration::from_millis(500));
     }
 }
 
+/// Runs only when no other task is ready
 fn idle() {
     loop {
-        enable();
-        hlt();
+        disable();
+        if SCHEDULAR.has_ready() {
+            enable();
+            SCHEDULAR.schedule();
+            continue;
+        }
+        // nothing to do until an interrupt wakes a task, no need to tick meanwhile
+        stop_tick();
+        enable_and_halt();
     }
 }
@@ -1,16 +1,20 @@
-use core::{alloc::Layout, ptr::NonNull};
-
-use alloc::boxed::Box;
+use core::ptr::NonNull;
 
 use crate::{
     memory::{
+        address_space::AddressSpace,
         gdt::{CS_SEL_KERNEL, DS_SEL_KERNEL, TSS},
-        page_table::{kernel_page_table, Level4, PageTable},
+        slab::{SlabBox, SlabCache},
+        stack::{KernelStack, StackOwner},
     },
     utils::stack::Stack,
 };
 
-const KERNEL_STACK_SIZE: usize = 0x1000;
+const KERNEL_STACK_SIZE: usize = 0x4000;
+
+pub static TASK_CACHE: SlabCache<X86Task> = SlabCache::new("task");
+
+pub type TaskBox = SlabBox<X86Task>;
 
 #[repr(C)]
 struct Task {
@@ -119,27 +123,55 @@ extern "C" fn context_switch(prev: &mut NonNull<Context>, next: &Context) {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    /// Waits in the run queue
+    Ready,
+    /// Waits for `Scheduler::wake_up`
+    Blocked,
+    /// Exited, dropped once nothing runs on its stack
+    Zombie,
+}
+
 #[repr(C)]
 pub struct X86Task {
     context: NonNull<Context>,
-    id: u8,
-    kernel_stack: Box<[u8]>,
-    page_table: NonNull<PageTable<Level4>>,
+    id: u64,
+    // the task runs on it in kernel mode, interrupts from user mode land on it
+    kernel_stack: KernelStack,
+    address_space: AddressSpace,
+    state: TaskState,
 }
 unsafe impl Sync for X86Task {}
 
+/// Save the registers of `prev` on its stack and continue `next` where it
+/// left off. Safe from interrupt context once the interrupt is acknowledged,
+/// the rest of the handler runs when `prev` is switched back to.
 pub fn x86_context_switch(prev: &mut X86Task, next: &X86Task) {
     unsafe {
-        TSS.privilege_stack_table[0] = {
-            let stack_end = next.kernel_stack.as_ptr() as *const _ as usize + KERNEL_STACK_SIZE;
-            stack_end as u64
-        };
-        next.page_table.as_ref().enable();
+        load(next);
         let next_c = next.context.as_ref();
         context_switch(&mut prev.context, next_c);
     }
 }
 
+/// Switch to `next` from a context that is never resumed, like the boot stack
+pub fn x86_start(next: &X86Task) -> ! {
+    let mut boot = NonNull::dangling();
+    unsafe {
+        load(next);
+        context_switch(&mut boot, next.context.as_ref());
+    }
+    unreachable!("the boot context was resumed");
+}
+
+/// Stack and address space `next` runs with
+unsafe fn load(next: &X86Task) {
+    TSS.privilege_stack_table[0] = next.kernel_stack.top();
+    next.address_space.activate();
+}
+
 impl X86Task {
     pub fn get_mut(&self) -> &mut X86Task {
         #[allow(invalid_reference_casting)]
@@ -147,24 +179,37 @@ impl X86Task {
             &mut *(self as *const _ as *mut _)
         }
     }
-    pub fn new_kernel(entry_point: u64, id: u8) -> X86Task {
-        let task_stack = unsafe {
-            alloc::alloc::alloc_zeroed(Layout::from_size_align_unchecked(KERNEL_STACK_SIZE, 0x1000))
-                .add(KERNEL_STACK_SIZE)
-        };
-        let switch_stack =
-            unsafe { alloc::alloc::alloc_zeroed(Layout::from_size_align_unchecked(0x100, 0x1000)) };
-        let mut stack_ptr = switch_stack as usize;
-        let mut stack = Stack::new(&mut stack_ptr);
-
-        let kframe = unsafe { stack.offset::<InterruptFrame>() };
-        *kframe = InterruptFrame::default();
+    pub fn new_kernel(entry_point: u64, id: u64) -> X86Task {
+        let mut kframe = InterruptFrame::default();
         kframe.ss = DS_SEL_KERNEL as usize;
         kframe.cs = CS_SEL_KERNEL as usize;
         kframe.rip = entry_point as usize;
-        kframe.rsp = task_stack as usize;
+        // set to the top of the kernel stack once it exists
+        kframe.rsp = 0;
         kframe.rflags = 0x200;
 
+        Self::with_frame(kframe, id, AddressSpace::new())
+    }
+
+    /// Copy of this task sharing its memory copy-on-write,
+    /// the copy starts by returning to user mode with the registers in `regs`
+    pub fn fork(&self, regs: InterruptFrame, id: u64) -> X86Task {
+        Self::with_frame(regs, id, self.address_space.clone())
+    }
+
+    fn with_frame(frame: InterruptFrame, id: u64, address_space: AddressSpace) -> X86Task {
+        let kernel_stack = KernelStack::new(KERNEL_STACK_SIZE, StackOwner::Task(id));
+        // the first switch to the task pops these from the top of its stack
+        let mut stack_ptr = kernel_stack.top() as usize;
+        let mut stack = Stack::new(&mut stack_ptr);
+
+        let kframe = unsafe { stack.offset::<InterruptFrame>() };
+        *kframe = frame;
+        // kernel tasks run on the same stack
+        if { kframe.cs } == CS_SEL_KERNEL as usize {
+            kframe.rsp = kernel_stack.top() as usize;
+        }
+
         let context = unsafe { stack.offset::<Context>() };
         *context = Context::default();
         context.rip = iretq_init as usize;
@@ -172,8 +217,25 @@ impl X86Task {
         Self {
             context: unsafe { NonNull::new_unchecked(context) },
             id,
-            kernel_stack: alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
-            page_table: unsafe { NonNull::new_unchecked(kernel_page_table()) },
+            kernel_stack,
+            address_space,
+            state: TaskState::Ready,
         }
     }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: TaskState) {
+        self.state = state;
+    }
+
+    pub fn boxed(self) -> TaskBox {
+        TASK_CACHE.boxed(self)
+    }
 }
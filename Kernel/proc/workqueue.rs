@@ -0,0 +1,122 @@
+use crate::{interrupts::run_without_interrupt, sync::spin::SpinMutex};
+
+use super::sheduler::{is_started, SCHEDULAR, WORKER_TASK_ID};
+
+// queued items per queue
+const WORK_QUEUE_SIZE: usize = 64;
+
+/// Called with the `ctx` it was queued with
+pub type WorkFn = fn(ctx: usize);
+
+#[derive(Clone, Copy)]
+pub struct Work {
+    pub func: WorkFn,
+    pub ctx: usize,
+}
+
+/// FIFO of work items, fixed size so interrupt handlers can queue without allocating
+pub struct WorkQueue {
+    items: [Option<Work>; WORK_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    // items that found the queue full since `take_dropped`
+    dropped: u64,
+}
+
+// run by the worker task
+static WORK: SpinMutex<WorkQueue> = SpinMutex::new(WorkQueue::new());
+
+impl WorkQueue {
+    pub const fn new() -> Self {
+        Self {
+            items: [None; WORK_QUEUE_SIZE],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Queue `work` after the items queued already. False if the queue is
+    /// full, the work is dropped and counted then, interrupt handlers can't wait.
+    pub fn push(&mut self, work: Work) -> bool {
+        if self.len == WORK_QUEUE_SIZE {
+            self.dropped += 1;
+            return false;
+        }
+        self.items[(self.head + self.len) % WORK_QUEUE_SIZE] = Some(work);
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % WORK_QUEUE_SIZE;
+        self.len -= 1;
+        work
+    }
+
+    /// Number of items dropped since the last call
+    pub fn take_dropped(&mut self) -> u64 {
+        core::mem::take(&mut self.dropped)
+    }
+}
+
+/// Have the worker task call `func` with `ctx`, from any context. Work that
+/// may take long or wait goes here, short work can be a tasklet.
+/// False if the queue was full and the work dropped.
+pub fn schedule_work(func: WorkFn, ctx: usize) -> bool {
+    let queued = run_without_interrupt(|| WORK.lock().push(Work { func, ctx }));
+    // before the start the worker is ready anyway
+    if is_started() {
+        SCHEDULAR.wake_up(WORKER_TASK_ID);
+    }
+    queued
+}
+
+/// Entry of the worker task, runs the queued work with interrupts enabled
+pub fn worker() {
+    loop {
+        let (work, dropped) = run_without_interrupt(|| {
+            let mut queue = WORK.lock();
+            let (work, dropped) = (queue.pop(), queue.take_dropped());
+            drop(queue);
+            if work.is_none() && dropped == 0 {
+                // interrupts stay off until the switch, no work can be queued in between
+                SCHEDULAR.block_current();
+            }
+            (work, dropped)
+        });
+        if dropped != 0 {
+            log!(
+                "workqueue: {} work items dropped, the queue was full",
+                dropped
+            );
+        }
+        if let Some(work) = work {
+            (work.func)(work.ctx);
+        }
+    }
+}
+
+#[test_case]
+fn test_full_queue_drops_work() {
+    fn nothing(_ctx: usize) {}
+    let mut queue = WorkQueue::new();
+    for ctx in 0..WORK_QUEUE_SIZE {
+        assert!(queue.push(Work { func: nothing, ctx }));
+    }
+    assert!(!queue.push(Work {
+        func: nothing,
+        ctx: WORK_QUEUE_SIZE
+    }));
+    assert!(queue.take_dropped() == 1);
+    assert!(queue.take_dropped() == 0);
+    // the queued items are kept, in order
+    for ctx in 0..WORK_QUEUE_SIZE {
+        assert!(queue.pop().map(|work| work.ctx) == Some(ctx));
+    }
+    assert!(queue.pop().is_none());
+}
@@ -1,3 +1,4 @@
 pub mod lazy;
 pub mod once;
+pub mod preempt;
 pub mod spin;
@@ -0,0 +1,17 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// spin locks held by the running code, the scheduler must not switch away from it meanwhile
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn preempt_disable() {
+    PREEMPT_COUNT.fetch_add(1, Ordering::Acquire);
+}
+
+pub fn preempt_enable() {
+    PREEMPT_COUNT.fetch_sub(1, Ordering::Release);
+}
+
+/// Whether the running code may be switched away from
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.load(Ordering::Relaxed) == 0
+}
@@ -4,6 +4,8 @@ use core::{
     sync::atomic::AtomicBool,
 };
 
+use super::preempt::{preempt_disable, preempt_enable};
+
 pub struct SpinMutex<T: ?Sized> {
     lock: AtomicBool,
     data: UnsafeCell<T>,
@@ -28,7 +30,9 @@ impl<T> SpinMutex<T> {
         }
     }
 
+    /// The holder isn't preempted, a task waiting for it would spin forever
     pub fn lock(&self) -> SpinMutexGuard<T> {
+        preempt_disable();
         while self
             .lock
             .compare_exchange_weak(
@@ -47,6 +51,28 @@ impl<T> SpinMutex<T> {
         }
     }
 
+    /// Take the lock only if it's free, for code that can't wait for the holder
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<T>> {
+        preempt_disable();
+        let locked = self
+            .lock
+            .compare_exchange(
+                false,
+                true,
+                core::sync::atomic::Ordering::Acquire,
+                core::sync::atomic::Ordering::Relaxed,
+            )
+            .is_ok();
+        if !locked {
+            preempt_enable();
+            return None;
+        }
+        Some(SpinMutexGuard {
+            lock: &self.lock,
+            data: unsafe { &mut *self.data.get() },
+        })
+    }
+
     #[inline(always)]
     pub fn is_locked(&self) -> bool {
         self.lock.load(core::sync::atomic::Ordering::Relaxed)
@@ -70,6 +96,7 @@ impl<'a, T> DerefMut for SpinMutexGuard<'a, T> {
 impl<'a, T: ?Sized> Drop for SpinMutexGuard<'a, T> {
     fn drop(&mut self) {
         self.lock
-            .store(false, core::sync::atomic::Ordering::Release)
+            .store(false, core::sync::atomic::Ordering::Release);
+        preempt_enable();
     }
 }
@@ -0,0 +1,67 @@
+use crate::{
+    memory::mmio::{ioremap, CacheMode, IoMem},
+    sync::once::Once,
+    utils::acpi,
+};
+
+use super::NS_PER_SEC;
+
+// register offsets
+const REG_CAPABILITIES: usize = 0x00;
+const REG_CONFIG: usize = 0x10;
+const REG_COUNTER: usize = 0xF0;
+
+const CAP_COUNTER_64: u64 = 1 << 13;
+const CONFIG_ENABLE: u64 = 1 << 0;
+// the spec allows at most 100ns per counter tick
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+// generic address structure of the register block in the ACPI table
+const TABLE_ADDRESS: usize = 40;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+pub static HPET: Once<Hpet> = Once::new();
+
+/// Main counter of the HPET, the comparators are left alone
+pub struct Hpet {
+    regs: IoMem,
+    period_fs: u64,
+}
+
+impl Hpet {
+    /// Map the HPET described by the ACPI "HPET" table and start its counter,
+    /// None if there is none or its counter is only 32 bits wide
+    pub fn probe() -> Option<Self> {
+        let table = acpi::find_table(b"HPET")?;
+        if table.read::<u8>(TABLE_ADDRESS) != ADDRESS_SPACE_MEMORY {
+            log!("hpet: registers are not memory mapped");
+            return None;
+        }
+        let phys = table.read::<u64>(TABLE_ADDRESS + 4);
+        let regs = ioremap(phys, 0x400, CacheMode::Uncached);
+        let caps = regs.read::<u64>(REG_CAPABILITIES);
+        let period_fs = caps >> 32;
+        // a 32 bit counter wraps within minutes
+        if period_fs == 0 || period_fs > MAX_PERIOD_FS || caps & CAP_COUNTER_64 == 0 {
+            log!("hpet: unusable, capabilities {:#X}", caps);
+            return None;
+        }
+        regs.reg::<u64>(REG_CONFIG)
+            .modify(|config| config | CONFIG_ENABLE);
+        log!(
+            "hpet: {:#X}, {} kHz",
+            phys,
+            FS_PER_NS * NS_PER_SEC / period_fs / 1000
+        );
+        Some(Self { regs, period_fs })
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.regs.read::<u64>(REG_COUNTER)
+    }
+
+    /// Nanoseconds since the counter started
+    pub fn ns(&self) -> u64 {
+        (self.counter() as u128 * self.period_fs as u128 / FS_PER_NS as u128) as u64
+    }
+}
@@ -0,0 +1,89 @@
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::instruction::{rdtsc, wrmsr},
+    interrupts::{
+        apic::{LocalApic, TimerMode, LOCAL_APIC},
+        idt::ExceptionFrame,
+        irq_exit,
+    },
+};
+
+use super::{busy_wait, now_ns, tsc, NS_PER_SEC};
+
+const MSR_IA32_TSC_DEADLINE: u64 = 0x6E0;
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+// timer steps per second, 0 until calibrated
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// Calibrate the local APIC timer and the TSC against the running clock and
+/// leave the timer idle in the best mode available. False without a local APIC.
+pub fn init() -> bool {
+    let apic = match LOCAL_APIC.get() {
+        Some(apic) => apic,
+        None => return false,
+    };
+    apic.setup_timer(TimerMode::OneShot, true);
+    let (start, tsc_start) = (now_ns(), rdtsc());
+    apic.start_timer(u32::MAX);
+    busy_wait(CALIBRATION_TIME);
+    let count = u32::MAX - apic.timer_count();
+    let (end, tsc_end) = (now_ns(), rdtsc());
+    apic.stop_timer();
+
+    let elapsed = end - start;
+    FREQUENCY.store(count as u64 * NS_PER_SEC / elapsed, Ordering::Relaxed);
+    let measured = (tsc_end - tsc_start) as u128 * NS_PER_SEC as u128 / elapsed as u128;
+    tsc::set_frequency(tsc::cpuid_frequency().unwrap_or(measured as u64));
+
+    // a deadline needs a TSC that keeps its rate
+    let mode = if tsc::is_invariant() && tsc::has_deadline_mode() {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::OneShot
+    };
+    TSC_DEADLINE.store(mode == TimerMode::TscDeadline, Ordering::Relaxed);
+    apic.setup_timer(mode, false);
+    log!(
+        "timer: local apic timer at {} kHz, {:?}, tsc at {} kHz",
+        FREQUENCY.load(Ordering::Relaxed) / 1000,
+        mode,
+        tsc::frequency() / 1000
+    );
+    true
+}
+
+fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.get().expect("no local apic")
+}
+
+/// Raise `TIMER_VECTOR` once the clock reaches `deadline_ns`, right away if it passed
+pub fn arm(deadline_ns: u64) {
+    let delta = deadline_ns.saturating_sub(now_ns());
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        wrmsr(MSR_IA32_TSC_DEADLINE, tsc::deadline_after(delta));
+    } else {
+        let count = delta as u128 * FREQUENCY.load(Ordering::Relaxed) as u128 / NS_PER_SEC as u128;
+        // 0 would stop the timer instead
+        local_apic().start_timer(count.clamp(1, u32::MAX as u128) as u32);
+    }
+}
+
+pub fn disarm() {
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        wrmsr(MSR_IA32_TSC_DEADLINE, 0);
+    } else {
+        local_apic().stop_timer();
+    }
+}
+
+pub extern "x86-interrupt" fn timer_interrupt(_frame: ExceptionFrame) {
+    super::timer_event();
+    local_apic().eoi();
+    irq_exit();
+}
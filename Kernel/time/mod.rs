@@ -0,0 +1,315 @@
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    interrupts::{
+        disable, enable, enable_and_halt,
+        irq::{register_irq, unregister_irq, IrqHandle},
+        is_enable, run_without_interrupt, TIMER_IRQ,
+    },
+    proc::sheduler::scheduler_tick,
+    sync::spin::SpinMutex,
+};
+
+use self::hpet::{Hpet, HPET};
+
+pub mod hpet;
+pub mod lapic;
+pub mod pit;
+pub mod timer;
+pub mod tsc;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+// ticks per second unless `init` is told otherwise
+pub const HZ: u32 = 100;
+
+/// What `now_ns` reads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Ticks counted plus the count of the PIT, needs the PIT interrupt
+    Pit,
+    Hpet,
+    /// Only used when it's invariant
+    Tsc,
+}
+
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+// length of a tick, 0 until `init`
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+// added to the clock source, so the clock goes on from where the last one left it
+static CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+// the clock never returns less than this
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+// the handler of the PIT interrupt, until the tick moves to the local APIC timer
+static PIT_HANDLER: SpinMutex<Option<IrqHandle>> = SpinMutex::new(None);
+// the local APIC timer fires only when asked to, the PIT is off
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+// clock time of the next tick, u64::MAX while the tick is stopped
+static NEXT_TICK: AtomicU64 = AtomicU64::new(u64::MAX);
+// earliest time asked for with `wake_at`, u64::MAX if there is none
+static NEXT_WAKEUP: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Start ticking `hz` times per second on the PIT
+pub fn init(hz: u32) {
+    let tick_ns = pit::set_frequency(hz);
+    TICK_NS.store(tick_ns, Ordering::Relaxed);
+    *PIT_HANDLER.lock() = Some(register_irq(TIMER_IRQ, timer_tick, 0));
+    log!("timer: pit at {} Hz, {} ns per tick", hz, tick_ns);
+}
+
+/// Move the clock to the HPET if ACPI lists one, needs the memory manager
+pub fn init_hpet() {
+    let hpet = match Hpet::probe() {
+        Some(hpet) => hpet,
+        None => return log!("timer: no hpet, the clock stays on the pit"),
+    };
+    let _ = HPET.call_once(|| Ok::<_, ()>(hpet));
+    set_clock_source(ClockSource::Hpet);
+}
+
+/// Replace the PIT tick with the local APIC timer, which is only armed for the
+/// next tick or wakeup. Needs a clock that runs without the PIT interrupt.
+pub fn init_tickless() {
+    if !lapic::init() {
+        return log!("timer: no local apic, staying with the pit tick");
+    }
+    if tsc::is_invariant() {
+        set_clock_source(ClockSource::Tsc);
+    } else if clock_source() == ClockSource::Pit {
+        return log!("timer: no clock without the pit, staying with the pit tick");
+    }
+    run_without_interrupt(|| {
+        if let Some(handle) = PIT_HANDLER.lock().take() {
+            unregister_irq(handle);
+        }
+        TICKLESS.store(true, Ordering::Relaxed);
+        NEXT_TICK.store(next_tick_after(now_ns()), Ordering::Relaxed);
+        reprogram();
+    });
+    log!(
+        "timer: tickless, the clock runs on the {:?}",
+        clock_source()
+    );
+}
+
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        0 => ClockSource::Pit,
+        1 => ClockSource::Hpet,
+        _ => ClockSource::Tsc,
+    }
+}
+
+fn set_clock_source(source: ClockSource) {
+    run_without_interrupt(|| {
+        let now = now_ns();
+        CLOCK_OFFSET.store(now.wrapping_sub(read_source(source)), Ordering::Relaxed);
+        CLOCK_SOURCE.store(source as u8, Ordering::Relaxed);
+    });
+}
+
+fn read_source(source: ClockSource) -> u64 {
+    match source {
+        ClockSource::Pit => run_without_interrupt(|| {
+            jiffies() * TICK_NS.load(Ordering::Relaxed) + pit::elapsed_ns()
+        }),
+        ClockSource::Hpet => HPET.get().expect("no hpet").ns(),
+        ClockSource::Tsc => tsc::ns(),
+    }
+}
+
+/// Work done once per tick, whichever timer drives it
+fn tick() {
+    scheduler_tick();
+}
+
+/// Handler of the timer IRQ
+fn timer_tick(_ctx: usize) -> bool {
+    JIFFIES.fetch_add(1, Ordering::Relaxed);
+    tick();
+    timer::poll(now_ns());
+    true
+}
+
+/// Called on the local APIC timer interrupt, runs the tick if one is due
+/// and arms the timer for whatever comes next
+fn timer_event() {
+    let now = now_ns();
+    if NEXT_WAKEUP.load(Ordering::Relaxed) <= now {
+        NEXT_WAKEUP.store(u64::MAX, Ordering::Relaxed);
+    }
+    if NEXT_TICK.load(Ordering::Relaxed) <= now {
+        update_jiffies(now);
+        tick();
+        // ticks that were missed are skipped, not run back to back
+        NEXT_TICK.store(next_tick_after(now), Ordering::Relaxed);
+    }
+    timer::poll(now);
+    reprogram();
+}
+
+fn next_tick_after(now: u64) -> u64 {
+    let tick_ns = TICK_NS.load(Ordering::Relaxed);
+    (now / tick_ns + 1) * tick_ns
+}
+
+fn update_jiffies(now: u64) {
+    JIFFIES.fetch_max(now / TICK_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Arm the local APIC timer for the earlier of the next tick and the next wakeup
+fn reprogram() {
+    let deadline = NEXT_TICK
+        .load(Ordering::Relaxed)
+        .min(NEXT_WAKEUP.load(Ordering::Relaxed));
+    if deadline == u64::MAX {
+        lapic::disarm();
+    } else {
+        lapic::arm(deadline);
+    }
+}
+
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed)
+}
+
+/// Stop the periodic tick until `restart_tick`, for a CPU going idle.
+/// Only the wakeups asked for with `wake_at` fire meanwhile.
+pub fn stop_tick() {
+    if !is_tickless() {
+        return;
+    }
+    run_without_interrupt(|| {
+        NEXT_TICK.store(u64::MAX, Ordering::Relaxed);
+        reprogram();
+    });
+}
+
+/// Resume the periodic tick, jiffies catch up with the time spent idle
+pub fn restart_tick() {
+    if !is_tickless() {
+        return;
+    }
+    run_without_interrupt(|| {
+        let now = now_ns();
+        update_jiffies(now);
+        NEXT_TICK.store(next_tick_after(now), Ordering::Relaxed);
+        reprogram();
+    });
+}
+
+/// Raise the timer interrupt no later than `deadline_ns`, the earliest deadline
+/// asked for wins. The periodic tick does it without being asked.
+pub fn wake_at(deadline_ns: u64) {
+    if !is_tickless() {
+        return;
+    }
+    run_without_interrupt(|| {
+        if deadline_ns < NEXT_WAKEUP.load(Ordering::Relaxed) {
+            NEXT_WAKEUP.store(deadline_ns, Ordering::Relaxed);
+            reprogram();
+        }
+    });
+}
+
+/// Ticks since `init`
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+/// Monotonic nanoseconds since boot
+pub fn now_ns() -> u64 {
+    let ns = read_source(clock_source()).wrapping_add(CLOCK_OFFSET.load(Ordering::Relaxed));
+    // the PIT count may wrap before the tick is handled, never go back
+    LAST_NS.fetch_max(ns, Ordering::Relaxed).max(ns)
+}
+
+/// Spin for at least `duration`, for short waits and code that can't sleep.
+/// The PIT clock only moves on with its interrupt, with interrupts disabled
+/// this needs the HPET or the TSC as clock source.
+pub fn busy_wait(duration: Duration) {
+    assert!(
+        TICK_NS.load(Ordering::Relaxed) != 0,
+        "the clock is not running yet"
+    );
+    assert!(
+        is_enable() || clock_source() != ClockSource::Pit,
+        "the pit clock stands still with interrupts disabled"
+    );
+    let deadline = now_ns() + duration.as_nanos() as u64;
+    while now_ns() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for at least `duration`, halting until the timer fires. Nothing would
+/// wake the CPU with interrupts disabled, then it spins instead, see `busy_wait`.
+pub fn sleep(duration: Duration) {
+    if !is_enable() {
+        return busy_wait(duration);
+    }
+    let deadline = now_ns() + duration.as_nanos() as u64;
+    loop {
+        disable();
+        if now_ns() >= deadline {
+            break enable();
+        }
+        wake_at(deadline);
+        enable_and_halt();
+    }
+}
+
+#[test_case]
+fn test_timers() {
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn count(ctx: usize) {
+        FIRED.fetch_add(ctx as u64, Ordering::Relaxed);
+    }
+    let start = now_ns();
+    timer::add_timer(Duration::from_millis(50), count, 1);
+    let cancelled = timer::add_timer(Duration::from_millis(20), count, 100);
+    let moved = timer::add_timer(Duration::from_millis(10), count, 10);
+    let periodic = timer::add_periodic(Duration::from_millis(20), count, 1000);
+    assert!(timer::cancel_timer(cancelled));
+    assert!(!timer::cancel_timer(cancelled));
+    assert!(timer::modify_timer(moved, Duration::from_millis(60)));
+
+    sleep(Duration::from_millis(30));
+    // the first period passed, more may have if the sleep overshot
+    let fired = FIRED.load(Ordering::Relaxed);
+    assert!(fired >= 1000 && fired % 1000 / 100 == 0, "fired {}", fired);
+    // the one-shots are due later
+    if now_ns() - start < 50_000_000 {
+        assert!(fired % 1000 == 0, "fired {}", fired);
+    }
+    sleep(Duration::from_millis(45));
+    assert!(timer::cancel_timer(periodic));
+    let fired = FIRED.load(Ordering::Relaxed);
+    assert!(fired % 1000 == 11 && fired / 1000 >= 3, "fired {}", fired);
+    // a fired one-shot timer can't be moved or cancelled anymore
+    assert!(!timer::modify_timer(moved, Duration::from_millis(10)));
+    assert!(!timer::cancel_timer(moved));
+    assert!(now_ns() - start >= 75_000_000);
+}
+
+#[test_case]
+fn test_periodic_timer_cancels_itself() {
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    static ID: SpinMutex<Option<timer::TimerId>> = SpinMutex::new(None);
+    fn third_run(_ctx: usize) {
+        if RUNS.fetch_add(1, Ordering::Relaxed) == 2 {
+            let id = ID.lock().expect("the timer ran before its id was stored");
+            assert!(timer::cancel_timer(id));
+        }
+    }
+    let id = timer::add_periodic(Duration::from_millis(5), third_run, 0);
+    *ID.lock() = Some(id);
+    sleep(Duration::from_millis(50));
+    assert!(RUNS.load(Ordering::Relaxed) == 3);
+    assert!(!timer::cancel_timer(id));
+}
@@ -0,0 +1,53 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{interrupts::run_without_interrupt, utils::port::Port};
+
+use super::NS_PER_SEC;
+
+/// Input clock of the PIT in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0_PORT: Port = Port::new(0x40);
+const COMMAND_PORT: Port = Port::new(0x43);
+/*
+|7    6|5    4|3    1|  0|
+|CHANNEL|ACCESS|  MODE|BCD|
+ */
+// channel 0, low then high byte, mode 2 (rate generator)
+const CMD_RATE_GENERATOR: u8 = 0b00_11_010_0;
+// channel 0, latch the current count
+const CMD_LATCH: u8 = 0b00_00_000_0;
+
+// reload value of channel 0, 0 until it's programmed
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+
+/// Raise IRQ0 `hz` times per second, returns the actual tick length in ns
+pub fn set_frequency(hz: u32) -> u64 {
+    assert!(
+        hz > 0 && hz as u64 <= PIT_FREQUENCY / 2,
+        "the pit can't tick at {} Hz",
+        hz
+    );
+    // mode 2 needs a divisor of at least 2, 0x10000 is written as 0
+    let divisor = ((PIT_FREQUENCY + hz as u64 / 2) / hz as u64).clamp(2, 0x10000) as u32;
+    run_without_interrupt(|| {
+        COMMAND_PORT.write_u8(CMD_RATE_GENERATOR);
+        CHANNEL0_PORT.write_u8(divisor as u8);
+        CHANNEL0_PORT.write_u8((divisor >> 8) as u8);
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+    divisor as u64 * NS_PER_SEC / PIT_FREQUENCY
+}
+
+/// Nanoseconds since the last tick, has to be called with interrupts disabled
+pub fn elapsed_ns() -> u64 {
+    let divisor = DIVISOR.load(Ordering::Relaxed) as u64;
+    if divisor == 0 {
+        return 0;
+    }
+    COMMAND_PORT.write_u8(CMD_LATCH);
+    let count = CHANNEL0_PORT.read_u8() as u64 | (CHANNEL0_PORT.read_u8() as u64) << 8;
+    // counts down from the divisor, a divisor of 0x10000 starts at 0
+    let count = if count == 0 { divisor } else { count };
+    (divisor - count.min(divisor)) * NS_PER_SEC / PIT_FREQUENCY
+}
@@ -0,0 +1,158 @@
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::collections::BTreeMap;
+
+use crate::{
+    interrupts::{
+        run_without_interrupt,
+        softirq::{raise, SoftIrq},
+    },
+    sync::spin::SpinMutex,
+};
+
+use super::{now_ns, wake_at};
+
+/// Called with the `ctx` it was added with, in softirq context
+pub type TimerCallback = fn(ctx: usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    callback: TimerCallback,
+    ctx: usize,
+    // None for one-shot timers
+    period: Option<u64>,
+}
+
+struct Timers {
+    // ordered by expiry, the id keeps equal deadlines apart
+    queue: BTreeMap<(u64, TimerId), Timer>,
+    deadlines: BTreeMap<TimerId, u64>,
+}
+
+static TIMERS: SpinMutex<Timers> = SpinMutex::new(Timers {
+    queue: BTreeMap::new(),
+    deadlines: BTreeMap::new(),
+});
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+// expiry of the first timer, u64::MAX if there is none
+static NEXT_EXPIRY: AtomicU64 = AtomicU64::new(u64::MAX);
+
+impl Timers {
+    fn insert(&mut self, id: TimerId, deadline: u64, timer: Timer) {
+        self.queue.insert((deadline, id), timer);
+        self.deadlines.insert(id, deadline);
+        self.update_expiry();
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        let deadline = self.deadlines.remove(&id)?;
+        let timer = self.queue.remove(&(deadline, id));
+        self.update_expiry();
+        timer
+    }
+
+    fn update_expiry(&self) {
+        let next = self
+            .queue
+            .keys()
+            .next()
+            .map_or(u64::MAX, |&(deadline, _)| deadline);
+        NEXT_EXPIRY.store(next, Ordering::Relaxed);
+        if next != u64::MAX {
+            // with the tick stopped nothing else would fire in time
+            wake_at(next);
+        }
+    }
+}
+
+fn add(delay: Duration, timer: Timer) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let deadline = now_ns() + delay.as_nanos() as u64;
+    run_without_interrupt(|| TIMERS.lock().insert(id, deadline, timer));
+    id
+}
+
+/// Call `callback` with `ctx` once, `delay` from now
+pub fn add_timer(delay: Duration, callback: TimerCallback, ctx: usize) -> TimerId {
+    let timer = Timer {
+        callback,
+        ctx,
+        period: None,
+    };
+    add(delay, timer)
+}
+
+/// Call `callback` with `ctx` every `period` until the timer is cancelled
+pub fn add_periodic(period: Duration, callback: TimerCallback, ctx: usize) -> TimerId {
+    assert!(!period.is_zero(), "a periodic timer needs a period");
+    let timer = Timer {
+        callback,
+        ctx,
+        period: Some(period.as_nanos() as u64),
+    };
+    add(period, timer)
+}
+
+/// Remove a timer, false if it already fired or was cancelled.
+/// A periodic timer can cancel itself from its callback.
+pub fn cancel_timer(id: TimerId) -> bool {
+    run_without_interrupt(|| TIMERS.lock().remove(id).is_some())
+}
+
+/// Fire the timer `delay` from now instead, false if it already fired or was cancelled
+pub fn modify_timer(id: TimerId, delay: Duration) -> bool {
+    let deadline = now_ns() + delay.as_nanos() as u64;
+    run_without_interrupt(|| {
+        let mut timers = TIMERS.lock();
+        match timers.remove(id) {
+            Some(timer) => {
+                timers.insert(id, deadline, timer);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Raise the timer softirq if a timer expired by `now`, called on every timer interrupt
+pub fn poll(now: u64) {
+    if NEXT_EXPIRY.load(Ordering::Relaxed) <= now {
+        raise(SoftIrq::Timer);
+    }
+}
+
+/// Handler of the timer softirq, calls the expired callbacks with interrupts enabled
+pub fn run_timers() {
+    loop {
+        let now = now_ns();
+        let expired = run_without_interrupt(|| {
+            let mut timers = TIMERS.lock();
+            let (&(deadline, id), _) = timers.queue.iter().next()?;
+            if deadline > now {
+                return None;
+            }
+            let timer = timers.remove(id)?;
+            if let Some(period) = timer.period {
+                // missed periods are skipped, not run back to back
+                let next = if deadline + period > now {
+                    deadline + period
+                } else {
+                    now + period
+                };
+                // back in before the callback runs, so it can cancel itself
+                timers.insert(id, next, timer);
+            }
+            Some(timer)
+        });
+        match expired {
+            Some(timer) => (timer.callback)(timer.ctx),
+            None => break,
+        }
+    }
+}
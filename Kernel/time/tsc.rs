@@ -0,0 +1,55 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::instruction::{cpuid, rdtsc};
+
+use super::NS_PER_SEC;
+
+// in Hz, 0 until it's known
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC ticks at a constant rate in every power state, so it can be a clock
+pub fn is_invariant() -> bool {
+    // CPUID.80000007H:EDX[bit 8]
+    let (max_leaf, _, _, _) = cpuid(0x8000_0000, 0);
+    max_leaf >= 0x8000_0007 && cpuid(0x8000_0007, 0).3 & 1 << 8 != 0
+}
+
+/// The local APIC timer supports firing at a TSC value
+pub fn has_deadline_mode() -> bool {
+    // CPUID.01H:ECX[bit 24]
+    cpuid(1, 0).2 & 1 << 24 != 0
+}
+
+/// Frequency from CPUID leaf 0x15, if the CPU reports it
+pub fn cpuid_frequency() -> Option<u64> {
+    let (max_leaf, _, _, _) = cpuid(0, 0);
+    if max_leaf < 0x15 {
+        return None;
+    }
+    // TSC = crystal clock * numerator / denominator
+    let (denominator, numerator, crystal_hz, _) = cpuid(0x15, 0);
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;
+    }
+    Some(crystal_hz as u64 * numerator as u64 / denominator as u64)
+}
+
+pub fn set_frequency(hz: u64) {
+    FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since the TSC was reset
+pub fn ns() -> u64 {
+    let hz = frequency();
+    assert!(hz != 0, "the tsc frequency is unknown");
+    (rdtsc() as u128 * NS_PER_SEC as u128 / hz as u128) as u64
+}
+
+/// The TSC value `ns` nanoseconds from now
+pub fn deadline_after(ns: u64) -> u64 {
+    rdtsc() + (ns as u128 * frequency() as u128 / NS_PER_SEC as u128) as u64
+}
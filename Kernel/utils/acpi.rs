@@ -0,0 +1,215 @@
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+
+use crate::{
+    memory::{
+        is_direct_mapped,
+        mmio::{ioremap, CacheMode},
+        phys_to_virt,
+    },
+    KERNEL_BASE,
+};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// the EBDA segment is stored here by the BIOS
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA: (u64, u64) = (0xE_0000, 0x10_0000);
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_IRQ_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // only valid from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// Copy of an ACPI table, header included
+pub struct Table {
+    data: Vec<u8>,
+}
+
+/// What the MADT tells about the interrupt controllers
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    // the legacy 8259 PICs are present and have to be masked
+    pub has_8259: bool,
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<MadtIoApic>,
+    pub overrides: Vec<IrqOverride>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+/// ISA IRQ `source` is wired to `gsi`, `flags` hold its polarity and trigger mode
+#[derive(Clone, Copy, Debug)]
+pub struct IrqOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl Table {
+    pub fn signature(&self) -> &[u8] {
+        &self.data[..4]
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The value at `offset` bytes into the table, ACPI fields are often misaligned
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(
+            offset + size_of::<T>() <= self.data.len(),
+            "read at {:#X} is beyond the table",
+            offset
+        );
+        unsafe { core::ptr::read_unaligned(self.data.as_ptr().add(offset) as *const T) }
+    }
+}
+
+/// Find the RSDP in the first KiB of the EBDA or in the BIOS area
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = unsafe { *((KERNEL_BASE + EBDA_POINTER) as *const u16) } as u64 * 16;
+    [(ebda, ebda + 1024), BIOS_AREA]
+        .iter()
+        .copied()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .find_map(|addr| {
+            let ptr = (KERNEL_BASE + addr) as *const u8;
+            let bytes = unsafe { core::slice::from_raw_parts(ptr, 20) };
+            if &bytes[..8] != RSDP_SIGNATURE || checksum(bytes) != 0 {
+                return None;
+            }
+            Some(unsafe { core::ptr::read_unaligned(ptr as *const Rsdp) })
+        })
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Copy `len` bytes at physical address `phys` into `dest`. The tables are in
+/// RAM, a mapping of them must not be uncached when the direct map caches them.
+fn read_phys(phys: u64, dest: &mut [u8]) {
+    let len = dest.len();
+    if is_direct_mapped(phys, len as u64) {
+        let src = phys_to_virt(phys) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(src, dest.as_mut_ptr(), len) };
+    } else {
+        let mem = ioremap(phys, len, CacheMode::WriteBack);
+        unsafe { core::ptr::copy_nonoverlapping(mem.addr() as *const u8, dest.as_mut_ptr(), len) };
+    }
+}
+
+/// Copy the table at physical address `phys` out of firmware memory
+fn load_table(phys: u64) -> Option<Table> {
+    let mut header = [0u8; size_of::<SdtHeader>()];
+    read_phys(phys, &mut header);
+    let len =
+        unsafe { core::ptr::read_unaligned(header.as_ptr() as *const SdtHeader) }.length as usize;
+
+    let mut data = alloc::vec![0u8; len];
+    read_phys(phys, &mut data);
+    if checksum(&data) != 0 {
+        log!("acpi: table at {:#X} has a bad checksum", phys);
+        return None;
+    }
+    Some(Table { data })
+}
+
+/// The first table with `signature`, found through the XSDT or the RSDT
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    let rsdp = find_rsdp()?;
+    let (root, entry_size) = if rsdp.revision >= 2 && { rsdp.xsdt_address } != 0 {
+        (load_table(rsdp.xsdt_address)?, 8)
+    } else {
+        (load_table(rsdp.rsdt_address as u64)?, 4)
+    };
+    (size_of::<SdtHeader>()..root.len())
+        .step_by(entry_size)
+        .map(|offset| match entry_size {
+            8 => root.read::<u64>(offset),
+            _ => root.read::<u32>(offset) as u64,
+        })
+        .filter_map(load_table)
+        .find(|table| table.signature() == signature)
+}
+
+/// Parse the MADT ("APIC" table), None if the firmware has none
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let header = size_of::<SdtHeader>();
+    let mut madt = Madt {
+        local_apic_address: table.read::<u32>(header) as u64,
+        has_8259: table.read::<u32>(header + 4) & 1 != 0,
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut offset = header + 8;
+    while offset + 2 <= table.len() {
+        let (typ, len) = (
+            table.read::<u8>(offset),
+            table.read::<u8>(offset + 1) as usize,
+        );
+        if len < 2 {
+            break;
+        }
+        match typ {
+            // bit 0 of the flags: the processor is enabled
+            MADT_LOCAL_APIC if table.read::<u32>(offset + 4) & 1 != 0 => {
+                madt.local_apic_ids.push(table.read(offset + 3))
+            }
+            MADT_IO_APIC => madt.io_apics.push(MadtIoApic {
+                id: table.read(offset + 2),
+                address: table.read::<u32>(offset + 4) as u64,
+                gsi_base: table.read(offset + 8),
+            }),
+            MADT_IRQ_OVERRIDE => madt.overrides.push(IrqOverride {
+                source: table.read(offset + 3),
+                gsi: table.read(offset + 4),
+                flags: table.read(offset + 8),
+            }),
+            MADT_LOCAL_APIC_OVERRIDE => madt.local_apic_address = table.read(offset + 4),
+            _ => {}
+        }
+        offset += len;
+    }
+    Some(madt)
+}
@@ -1,9 +1,10 @@
-pub mod backtrace;
 pub mod logging;
 
 #[macro_use]
 pub mod macros;
 
+pub mod acpi;
+pub mod backtrace;
 pub mod cursor;
 pub mod pci;
 pub mod port;